@@ -0,0 +1,923 @@
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU64;
+use std::ops::ControlFlow;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::Result;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::metadata::LoopCount;
+use image::{imageops, AnimationDecoder, Frames, ImageDecoder, ImageFormat, Rgba, RgbaImage};
+
+use crate::ingest::{rejected_image, Limits, Rejected};
+
+type GifReader<'d> = gif::Decoder<io::Cursor<&'d [u8]>>;
+
+/// Re-write a gif frame-by-frame. Anything we don't understand (comments, xmp, ...)
+/// is dropped, but the palettes, timing, disposal and loop count are kept.
+///
+/// With `optimise`, also try storing only the pixels which change between frames,
+/// and keep whichever comes out smaller.
+pub fn reencode_gif(data: &[u8], limits: &Limits, optimise: bool) -> Result<Vec<u8>> {
+    let verbatim = copy_gif(data, limits)?;
+    if !optimise {
+        return Ok(verbatim);
+    }
+
+    match optimise_gif(data, limits)? {
+        Some(optimised) if optimised.len() < verbatim.len() => Ok(optimised),
+        _ => Ok(verbatim),
+    }
+}
+
+fn copy_gif(data: &[u8], limits: &Limits) -> Result<Vec<u8>> {
+    let mut reader = gif_reader(data, limits, gif::ColorOutput::Indexed)?;
+    let mut out = Vec::with_capacity(data.len());
+
+    {
+        let mut encoder = gif::Encoder::new(
+            &mut out,
+            reader.width(),
+            reader.height(),
+            reader.global_palette().unwrap_or(&[]),
+        )
+        .with_context(|| anyhow!("preparing gif"))?;
+        encoder.set_repeat(reader.repeat())?;
+
+        let mut frames = 0;
+        while let Some(frame) = next_frame(&mut reader, limits, &mut frames)? {
+            encoder
+                .write_frame(frame)
+                .with_context(|| anyhow!("writing frame"))?;
+        }
+    }
+
+    Ok(out)
+}
+
+/// `None` if some frame needs more than 255 colours, which the original might have
+/// managed by having multiple frames share the screen, or if the patches, which are
+/// all held until the end, would need more than `limits.max_alloc`
+fn optimise_gif(data: &[u8], limits: &Limits) -> Result<Option<Vec<u8>>> {
+    let mut reader = gif_reader(data, limits, gif::ColorOutput::RGBA)?;
+    let (width, height) = (reader.width(), reader.height());
+
+    let mut canvas = Canvas::new(width, height);
+    let mut patcher = GifPatcher::new(width, height, limits.max_alloc);
+
+    let mut frames = 0;
+    while let Some(frame) = next_frame(&mut reader, limits, &mut frames)? {
+        let delay = frame.delay;
+        if !patcher.push(canvas.show(frame), delay) {
+            return Ok(None);
+        }
+    }
+
+    patcher.finish(reader.repeat())
+}
+
+/// An animation as it's seen: every frame is the whole picture.
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    /// each frame, and how long it's shown for, in milliseconds
+    pub frames: Vec<(RgbaImage, u32)>,
+    /// how many times it's played through; `None` is forever
+    pub plays: Option<u32>,
+}
+
+impl Animation {
+    fn check_size(&self, limits: &Limits) -> Result<(), Rejected> {
+        let frame_size = u64::from(self.width) * u64::from(self.height) * 4;
+        if self.frames.len() > limits.max_frames {
+            return Err(Rejected::TooLarge(format!(
+                "too many frames, limit: {}",
+                limits.max_frames
+            )));
+        }
+        if (self.frames.len() as u64).saturating_mul(frame_size) > limits.max_alloc {
+            return Err(Rejected::TooLarge(format!(
+                "animation too large to decode: {} {}x{} frames",
+                self.frames.len(),
+                self.width,
+                self.height
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A gif, played through, or `None` if holding every frame in memory at once,
+/// as the encoders need to, would break `limits.max_alloc`.
+pub fn gif_animation(data: &[u8], limits: &Limits) -> Result<Option<Animation>> {
+    let mut reader = gif_reader(data, limits, gif::ColorOutput::RGBA)?;
+    let (width, height) = (reader.width(), reader.height());
+
+    let mut canvas = Canvas::new(width, height);
+    let mut animation = Animation {
+        width: u32::from(width),
+        height: u32::from(height),
+        frames: Vec::new(),
+        plays: None,
+    };
+    let mut frames = 0;
+    while let Some(frame) = next_frame(&mut reader, limits, &mut frames)? {
+        // gif delays are in hundredths of a second
+        let delay = u32::from(frame.delay) * 10;
+        animation.frames.push((canvas.show(frame), delay));
+        if animation.check_size(limits).is_err() {
+            return Ok(None);
+        }
+    }
+
+    animation.plays = match reader.repeat() {
+        gif::Repeat::Infinite => None,
+        // gifs count the repeats, not the plays
+        gif::Repeat::Finite(repeats) => Some(u32::from(repeats) + 1),
+    };
+    Ok(Some(animation))
+}
+
+/// Animated pngs and webps can have a single frame, and `image` can't
+/// animate 16-bit pngs, so this isn't just "is it animated?"
+pub fn is_animation(data: &[u8], format: ImageFormat) -> bool {
+    if format == ImageFormat::Gif {
+        return gif_is_animated(data);
+    }
+
+    let data = io::Cursor::new(data);
+    match format {
+        ImageFormat::Png => PngDecoder::new(data).is_ok_and(|decoder| {
+            decoder.is_apng().unwrap_or(false) && decoder.color_type().bytes_per_pixel() <= 4
+        }),
+        ImageFormat::WebP => WebPDecoder::new(data).is_ok_and(|decoder| decoder.has_animation()),
+        _ => false,
+    }
+}
+
+/// two frames or more, found without decoding any pixels,
+/// so it's cheap enough to ask about anything we've stored
+fn gif_is_animated(data: &[u8]) -> bool {
+    let mut options = gif::DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let Ok(mut reader) = options.read_info(io::Cursor::new(data)) else {
+        return false;
+    };
+    (0..2).all(|_| matches!(reader.read_next_frame(), Ok(Some(_))))
+}
+
+/// An animated png or webp, played through.
+pub fn decode_animation(data: &[u8], format: ImageFormat, limits: &Limits) -> Result<Animation> {
+    let (width, height, plays, frames) = image_frames(data, format, limits)?;
+
+    let mut animation = Animation {
+        width,
+        height,
+        frames: Vec::new(),
+        plays: match plays {
+            LoopCount::Infinite => None,
+            LoopCount::Finite(plays) => Some(plays.get()),
+        },
+    };
+    for frame in frames {
+        let frame = frame.map_err(rejected_image)?;
+        let delay = frame_delay(&frame);
+        animation.frames.push((frame.into_buffer(), delay));
+        animation.check_size(limits)?;
+    }
+
+    Ok(animation)
+}
+
+/// Every frame of a stored animation, as it's seen, and how long it's shown for (in ms),
+/// until `each` breaks. Unlike `decode_animation`, the frames aren't all held at once.
+pub fn each_frame(
+    data: &[u8],
+    format: ImageFormat,
+    limits: &Limits,
+    mut each: impl FnMut(RgbaImage, u32) -> ControlFlow<()>,
+) -> Result<()> {
+    if format == ImageFormat::Gif {
+        let mut reader = gif_reader(data, limits, gif::ColorOutput::RGBA)?;
+        let mut canvas = Canvas::new(reader.width(), reader.height());
+        let mut frames = 0;
+        while let Some(frame) = next_frame(&mut reader, limits, &mut frames)? {
+            if each(canvas.show(frame), u32::from(frame.delay) * 10).is_break() {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let (_, _, _, frames) = image_frames(data, format, limits)?;
+    for (i, frame) in frames.enumerate() {
+        if i >= limits.max_frames {
+            return Err(Rejected::TooLarge(format!(
+                "too many frames, limit: {}",
+                limits.max_frames
+            ))
+            .into());
+        }
+        let frame = frame.map_err(rejected_image)?;
+        let delay = frame_delay(&frame);
+        if each(frame.into_buffer(), delay).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn frame_delay(frame: &image::Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    numer / denom.max(1)
+}
+
+/// `image`'s view of an animated png or webp: the size, loop count, and (lazy) frames
+fn image_frames<'d>(
+    data: &'d [u8],
+    format: ImageFormat,
+    limits: &Limits,
+) -> Result<(u32, u32, LoopCount, Frames<'d>)> {
+    let cursor = io::Cursor::new(data);
+    Ok(match format {
+        ImageFormat::Png => {
+            let decoder =
+                PngDecoder::with_limits(cursor, limits.image_limits()).map_err(rejected_image)?;
+            let (width, height) = decoder.dimensions();
+            limits.check_dimensions(width, height)?;
+            let decoder = decoder.apng().map_err(rejected_image)?;
+            (width, height, decoder.loop_count(), decoder.into_frames())
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(cursor).map_err(rejected_image)?;
+            let (width, height) = decoder.dimensions();
+            limits.check_dimensions(width, height)?;
+            // which is what browsers do, whatever the file says
+            decoder
+                .set_background_color(Rgba([0; 4]))
+                .map_err(rejected_image)?;
+            (width, height, decoder.loop_count(), decoder.into_frames())
+        }
+        _ => bail!("{format:?} isn't animated"),
+    })
+}
+
+/// as an animated webp, lossless unless there's a `quality`
+pub fn encode_webp(animation: &Animation, quality: Option<f32>) -> Result<Vec<u8>> {
+    let mut config = webp::WebPConfig::new().map_err(|()| anyhow!("webp config"))?;
+    match quality {
+        Some(quality) => config.quality = quality,
+        None => config.lossless = 1,
+    }
+
+    let (width, height) = (animation.width, animation.height);
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(match animation.plays {
+        None => 0,
+        Some(plays) => i32::try_from(plays).unwrap_or(i32::MAX),
+    });
+
+    let mut timestamp = 0i32;
+    for (pixels, delay) in &animation.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(pixels, width, height, timestamp));
+        timestamp = timestamp.saturating_add_unsigned(*delay);
+    }
+
+    let mut out = encoder
+        .try_encode()
+        .map_err(|e| anyhow!("encoding webp: {e:?}"))?
+        .to_vec();
+    set_webp_duration(&mut out, timestamp)?;
+    Ok(out)
+}
+
+/// as a gif, or `None` if that would lose something: partial transparency,
+/// timing which isn't in hundredths of a second, or too many colours;
+/// or if building it would need more than `limits.max_alloc`
+pub fn encode_gif(animation: &Animation, limits: &Limits) -> Result<Option<Vec<u8>>> {
+    let (Ok(width), Ok(height)) = (
+        u16::try_from(animation.width),
+        u16::try_from(animation.height),
+    ) else {
+        return Ok(None);
+    };
+
+    let mut patcher = GifPatcher::new(width, height, limits.max_alloc);
+    for (pixels, delay) in &animation.frames {
+        if pixels.pixels().any(|px| px[3] != 0 && px[3] != 255) {
+            return Ok(None);
+        }
+        if delay % 10 != 0 {
+            return Ok(None);
+        }
+        let Ok(delay) = u16::try_from(delay / 10) else {
+            return Ok(None);
+        };
+        if !patcher.push(pixels.clone(), delay) {
+            return Ok(None);
+        }
+    }
+
+    patcher.finish(match animation.plays {
+        None => gif::Repeat::Infinite,
+        Some(plays) => gif::Repeat::Finite(u16::try_from(plays - 1).unwrap_or(u16::MAX)),
+    })
+}
+
+/// libwebp is only told when each frame starts, and the `webp` crate doesn't
+/// tell it when the last one ends, so it makes something up. Fix that, by
+/// making the final frame last until `total` (ms).
+fn set_webp_duration(webp: &mut [u8], total: i32) -> Result<()> {
+    // RIFF, size, WEBP, then chunks of: fourcc, le32 size, data (padded to even)
+    let mut offset = 12;
+    let mut elapsed = 0;
+    let mut last_frame = None;
+    while offset + 8 <= webp.len() {
+        let len = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into()?) as usize;
+        if &webp[offset..offset + 4] == b"ANMF" {
+            // x, y, width - 1, height - 1, duration: all le24
+            let duration = &webp[offset + 8 + 12..offset + 8 + 15];
+            let duration = i32::from_le_bytes([duration[0], duration[1], duration[2], 0]);
+            if let Some(previous) = last_frame.replace((offset, duration)) {
+                elapsed += previous.1;
+            }
+        }
+        offset += 8 + len + len % 2;
+    }
+
+    let (offset, _) = last_frame.ok_or_else(|| anyhow!("no frames in animated webp"))?;
+    let duration = (total - elapsed).clamp(0, 0xff_ff_ff);
+    webp[offset + 8 + 12..offset + 8 + 15].copy_from_slice(&duration.to_le_bytes()[..3]);
+    Ok(())
+}
+
+fn gif_reader<'d>(
+    data: &'d [u8],
+    limits: &Limits,
+    output: gif::ColorOutput,
+) -> Result<GifReader<'d>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(output);
+    if let Some(max_alloc) = NonZeroU64::new(limits.max_alloc) {
+        options.set_memory_limit(gif::MemoryLimit::Bytes(max_alloc));
+    }
+    let reader = options
+        .read_info(io::Cursor::new(data))
+        .map_err(rejected_gif)
+        .with_context(|| anyhow!("loading gif"))?;
+
+    limits.check_dimensions(u32::from(reader.width()), u32::from(reader.height()))?;
+    Ok(reader)
+}
+
+fn next_frame<'r>(
+    reader: &'r mut GifReader<'_>,
+    limits: &Limits,
+    frames: &mut usize,
+) -> Result<Option<&'r gif::Frame<'static>>> {
+    let frame = reader
+        .read_next_frame()
+        .map_err(rejected_gif)
+        .with_context(|| anyhow!("reading frame"))?;
+
+    if frame.is_some() {
+        *frames += 1;
+        if *frames > limits.max_frames {
+            return Err(Rejected::TooLarge(format!(
+                "too many frames, limit: {}",
+                limits.max_frames
+            ))
+            .into());
+        }
+    }
+
+    Ok(frame)
+}
+
+fn rejected_gif(e: gif::DecodingError) -> Error {
+    use gif::DecodingError;
+    let rejected = match &e {
+        DecodingError::MemoryLimit | DecodingError::OutOfMemory => {
+            Rejected::TooLarge("gif too large to decode".to_string())
+        }
+        DecodingError::Io(_) => return e.into(),
+        _ => Rejected::Malformed("couldn't decode gif"),
+    };
+    Error::new(e).context(rejected)
+}
+
+/// Builds a gif out of whole frames, by storing only what changes between them.
+struct GifPatcher {
+    width: u16,
+    height: u16,
+    last_shown: RgbaImage,
+    patches: Vec<Patch>,
+    /// roughly, how much memory the patches (and `last_shown`) are using
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl GifPatcher {
+    fn new(width: u16, height: u16, max_bytes: u64) -> GifPatcher {
+        let last_shown = RgbaImage::new(u32::from(width), u32::from(height));
+        GifPatcher {
+            width,
+            height,
+            bytes: last_shown.len() as u64,
+            last_shown,
+            patches: Vec::new(),
+            max_bytes,
+        }
+    }
+
+    /// `shown` must be entirely opaque or transparent; `delay` is in hundredths of a second.
+    /// `false` if the patches have grown past `max_bytes`, so the gif should be given up on.
+    #[must_use]
+    fn push(&mut self, shown: RgbaImage, delay: u16) -> bool {
+        // patches only draw, so anything which needs to disappear has to be
+        // cleared away by the previous patch, once it's done being shown
+        let mut base = std::mem::take(&mut self.last_shown);
+        let cleared = match (
+            bounds(&base, &shown, needs_clearing),
+            self.patches.last_mut(),
+        ) {
+            (Some(clearing), Some(previous)) => {
+                let before = previous.pixels.len() as u64;
+                previous.cover(clearing);
+                self.bytes = self.bytes - before + previous.pixels.len() as u64;
+                previous.dispose = gif::DisposalMethod::Background;
+                previous.clear(&mut base);
+                true
+            }
+            // at the start, nothing has been drawn, so nothing needs clearing
+            _ => false,
+        };
+
+        match bounds(&base, &shown, |before, after| before != after) {
+            Some(area) => self.add(Patch::new(&base, &shown, area, delay)),
+            None => match self.patches.last_mut() {
+                Some(previous) if !cleared => previous.delay = previous.delay.saturating_add(delay),
+                _ => self.add(Patch::blank(delay)),
+            },
+        }
+        self.last_shown = shown;
+        self.bytes <= self.max_bytes
+    }
+
+    fn add(&mut self, patch: Patch) {
+        self.bytes += patch.pixels.len() as u64;
+        self.patches.push(patch);
+    }
+
+    /// `None` if some patch needs more than 255 colours
+    fn finish(self, repeat: gif::Repeat) -> Result<Option<Vec<u8>>> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut out, self.width, self.height, &[])
+                .with_context(|| anyhow!("preparing gif"))?;
+            encoder.set_repeat(repeat)?;
+            for patch in &self.patches {
+                let Some(frame) = patch.to_frame() else {
+                    return Ok(None);
+                };
+                encoder
+                    .write_frame(&frame)
+                    .with_context(|| anyhow!("writing frame"))?;
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+/// What a viewer is showing, as frames (decoded to rgba) are played onto it.
+struct Canvas {
+    pixels: RgbaImage,
+}
+
+impl Canvas {
+    fn new(width: u16, height: u16) -> Canvas {
+        Canvas {
+            pixels: RgbaImage::new(u32::from(width), u32::from(height)),
+        }
+    }
+
+    /// draw the frame, returning what's then shown, and dispose of it ready for the next
+    fn show(&mut self, frame: &gif::Frame<'_>) -> RgbaImage {
+        let restore = (frame.dispose == gif::DisposalMethod::Previous).then(|| self.pixels.clone());
+
+        let (left, top) = (u32::from(frame.left), u32::from(frame.top));
+        let (width, height) = self.pixels.dimensions();
+        if frame.width > 0 {
+            let rows = frame.buffer.chunks_exact(4 * usize::from(frame.width));
+            for (y, row) in (top..height).zip(rows) {
+                for (x, px) in (left..width).zip(row.chunks_exact(4)) {
+                    // gif pixels are either entirely transparent, or opaque
+                    if px[3] != 0 {
+                        self.pixels
+                            .put_pixel(x, y, Rgba([px[0], px[1], px[2], px[3]]));
+                    }
+                }
+            }
+        }
+
+        let shown = self.pixels.clone();
+
+        if frame.dispose == gif::DisposalMethod::Background {
+            // everyone treats the background as transparent, whatever the file says
+            let right = (left + u32::from(frame.width)).min(width);
+            let bottom = (top + u32::from(frame.height)).min(height);
+            for y in top..bottom {
+                for x in left..right {
+                    self.pixels.put_pixel(x, y, Rgba([0; 4]));
+                }
+            }
+        }
+        if let Some(restore) = restore {
+            self.pixels = restore;
+        }
+
+        shown
+    }
+}
+
+/// left, top, right, bottom, inclusive
+type Area = (u32, u32, u32, u32);
+
+/// the smallest area containing every pixel for which `interesting(before, after)`
+fn bounds(
+    before: &RgbaImage,
+    after: &RgbaImage,
+    interesting: impl Fn(&Rgba<u8>, &Rgba<u8>) -> bool,
+) -> Option<Area> {
+    let mut area: Option<Area> = None;
+    for (x, y, px) in after.enumerate_pixels() {
+        if interesting(before.get_pixel(x, y), px) {
+            area = Some(match area {
+                None => (x, y, x, y),
+                Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x), b.max(y)),
+            });
+        }
+    }
+    area
+}
+
+fn needs_clearing(before: &Rgba<u8>, after: &Rgba<u8>) -> bool {
+    before[3] != 0 && after[3] == 0
+}
+
+/// An area to draw over the previous frame; transparent pixels are left alone.
+struct Patch {
+    left: u32,
+    top: u32,
+    pixels: RgbaImage,
+    delay: u16,
+    dispose: gif::DisposalMethod,
+}
+
+impl Patch {
+    /// Draws what's changed in `area`, so `before` becomes `after`, which must
+    /// not need anything clearing. This is exact; no colours are reduced.
+    fn new(before: &RgbaImage, after: &RgbaImage, area: Area, delay: u16) -> Patch {
+        let (left, top, right, bottom) = area;
+        let pixels = RgbaImage::from_fn(right - left + 1, bottom - top + 1, |x, y| {
+            let (x, y) = (x + left, y + top);
+            let px = after.get_pixel(x, y);
+            if px == before.get_pixel(x, y) {
+                Rgba([0; 4])
+            } else {
+                *px
+            }
+        });
+        Patch {
+            left,
+            top,
+            pixels,
+            delay,
+            dispose: gif::DisposalMethod::Keep,
+        }
+    }
+
+    /// a single transparent pixel, for when only time passes
+    fn blank(delay: u16) -> Patch {
+        Patch {
+            left: 0,
+            top: 0,
+            pixels: RgbaImage::new(1, 1),
+            delay,
+            dispose: gif::DisposalMethod::Keep,
+        }
+    }
+
+    /// grow, with transparency, to include `area`
+    fn cover(&mut self, area: Area) {
+        let (left, top) = (self.left.min(area.0), self.top.min(area.1));
+        let right = (self.left + self.pixels.width() - 1).max(area.2);
+        let bottom = (self.top + self.pixels.height() - 1).max(area.3);
+        let mut pixels = RgbaImage::new(right - left + 1, bottom - top + 1);
+        imageops::replace(
+            &mut pixels,
+            &self.pixels,
+            i64::from(self.left - left),
+            i64::from(self.top - top),
+        );
+        self.left = left;
+        self.top = top;
+        self.pixels = pixels;
+    }
+
+    /// what `gif::DisposalMethod::Background` does to the screen
+    fn clear(&self, screen: &mut RgbaImage) {
+        for y in 0..self.pixels.height() {
+            for x in 0..self.pixels.width() {
+                screen.put_pixel(self.left + x, self.top + y, Rgba([0; 4]));
+            }
+        }
+    }
+
+    /// `None` if there are too many colours to palette
+    fn to_frame(&self) -> Option<gif::Frame<'static>> {
+        const TRANSPARENT: u8 = 0;
+        let mut palette = vec![0u8; 3];
+        let mut indexes = HashMap::new();
+        let mut pixels = Vec::with_capacity(self.pixels.len() / 4);
+
+        for px in self.pixels.pixels() {
+            if px[3] == 0 {
+                pixels.push(TRANSPARENT);
+                continue;
+            }
+            let rgb = [px[0], px[1], px[2]];
+            let index = match indexes.get(&rgb) {
+                Some(&index) => index,
+                None => {
+                    let index = u8::try_from(indexes.len() + 1).ok()?;
+                    palette.extend_from_slice(&rgb);
+                    indexes.insert(rgb, index);
+                    index
+                }
+            };
+            pixels.push(index);
+        }
+
+        // everything came from a gif, so it fits
+        let mut frame = gif::Frame::from_palette_pixels(
+            self.pixels.width() as u16,
+            self.pixels.height() as u16,
+            pixels,
+            palette,
+            Some(TRANSPARENT),
+        );
+        frame.left = self.left as u16;
+        frame.top = self.top as u16;
+        frame.delay = self.delay;
+        frame.dispose = self.dispose;
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::Canvas;
+    use crate::ingest::Limits;
+
+    /// every distinct picture shown, and for how long
+    fn playback(data: &[u8]) -> (gif::Repeat, Vec<(RgbaImage, u32)>) {
+        let mut reader =
+            super::gif_reader(data, &Limits::default(), gif::ColorOutput::RGBA).unwrap();
+        let mut canvas = Canvas::new(reader.width(), reader.height());
+        let mut shown: Vec<(RgbaImage, u32)> = Vec::new();
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            let delay = u32::from(frame.delay);
+            let pixels = canvas.show(frame);
+            match shown.last_mut() {
+                Some((last, time)) if *last == pixels => *time += delay,
+                _ => shown.push((pixels, delay)),
+            }
+        }
+        (reader.repeat(), shown)
+    }
+
+    /// like `playback`, but for a webp, decoded by libwebp (`image` rounds when blending)
+    fn webp_playback(data: &[u8]) -> Vec<(RgbaImage, u32)> {
+        let anim = webp::AnimDecoder::new(data).decode().unwrap();
+        let mut shown: Vec<(RgbaImage, u32)> = Vec::new();
+        let mut ended = 0;
+        for frame in (0..anim.len()).map(|i| anim.get_frame(i).unwrap()) {
+            // libwebp gives the time each frame ends, in ms
+            let delay = (frame.get_time_ms() - ended) as u32 / 10;
+            ended = frame.get_time_ms();
+            let pixels =
+                RgbaImage::from_raw(frame.width(), frame.height(), frame.get_image().to_vec())
+                    .unwrap();
+            match shown.last_mut() {
+                Some((last, time)) if *last == pixels => *time += delay,
+                _ => shown.push((pixels, delay)),
+            }
+        }
+        shown
+    }
+
+    fn assert_same(expected: &[(RgbaImage, u32)], actual: &[(RgbaImage, u32)]) {
+        let delays = |shown: &[(RgbaImage, u32)]| shown.iter().map(|(_, d)| *d).collect::<Vec<_>>();
+        assert_eq!(delays(expected), delays(actual));
+        for ((expected, _), (actual, _)) in expected.iter().zip(actual) {
+            // the colour of invisible pixels doesn't matter, and isn't kept
+            let visible = |px: &image::Rgba<u8>| if px[3] == 0 { [0; 4] } else { px.0 };
+            assert!(expected
+                .pixels()
+                .map(visible)
+                .eq(actual.pixels().map(visible)));
+        }
+    }
+
+    fn gif_to_webp(data: &[u8], limits: &Limits) -> Option<Vec<u8>> {
+        let animation = super::gif_animation(data, limits).unwrap()?;
+        Some(super::encode_webp(&animation, None).unwrap())
+    }
+
+    #[test]
+    fn webp() {
+        let parrot = include_bytes!("../tests/parrot.gif");
+        let webp = gif_to_webp(parrot, &Limits::default()).unwrap();
+        assert_same(&playback(parrot).1, &webp_playback(&webp));
+        assert_eq!(
+            0,
+            webp::AnimDecoder::new(&webp).decode().unwrap().loop_count
+        );
+
+        let gif = handmade(gif::Repeat::Finite(3));
+        let webp = gif_to_webp(&gif, &Limits::default()).unwrap();
+        assert_same(&playback(&gif).1, &webp_playback(&webp));
+        assert_eq!(
+            4,
+            webp::AnimDecoder::new(&webp).decode().unwrap().loop_count
+        );
+
+        let limits = Limits {
+            max_alloc: 4 * 2 * 4,
+            ..Limits::default()
+        };
+        assert!(super::gif_animation(&gif, &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn apng() {
+        use image::ImageFormat;
+
+        let apng = include_bytes!("../tests/anim.png");
+        assert!(super::is_animation(apng, ImageFormat::Png));
+        assert!(!super::is_animation(
+            include_bytes!("../tests/alpha.png"),
+            ImageFormat::Png
+        ));
+
+        let animation =
+            super::decode_animation(apng, ImageFormat::Png, &Limits::default()).unwrap();
+        assert_eq!(Some(2), animation.plays);
+        assert_eq!(
+            vec![100, 200],
+            animation.frames.iter().map(|(_, d)| *d).collect::<Vec<_>>()
+        );
+        let (second, _) = &animation.frames[1];
+        assert_eq!([255, 0, 0, 255], second.get_pixel(0, 0).0);
+        assert_eq!([0, 0, 255, 255], second.get_pixel(7, 7).0);
+
+        let as_delays = |frames: &[(RgbaImage, u32)]| {
+            frames
+                .iter()
+                .map(|(pixels, delay)| (pixels.clone(), delay / 10))
+                .collect::<Vec<_>>()
+        };
+
+        let gif = super::encode_gif(&animation, &Limits::default())
+            .unwrap()
+            .unwrap();
+        let (repeat, shown) = playback(&gif);
+        assert_eq!(gif::Repeat::Finite(1), repeat);
+        assert_same(&as_delays(&animation.frames), &shown);
+
+        let webp = super::encode_webp(&animation, None).unwrap();
+        assert!(super::is_animation(&webp, ImageFormat::WebP));
+        assert_same(&as_delays(&animation.frames), &webp_playback(&webp));
+        assert_eq!(
+            2,
+            webp::AnimDecoder::new(&webp).decode().unwrap().loop_count
+        );
+
+        let limits = Limits {
+            max_frames: 1,
+            ..Limits::default()
+        };
+        assert!(super::decode_animation(apng, ImageFormat::Png, &limits).is_err());
+    }
+
+    #[test]
+    fn parrot() {
+        let parrot = include_bytes!("../tests/parrot.gif");
+        let limits = Limits::default();
+        let copied = super::copy_gif(parrot, &limits).unwrap();
+        let optimised = super::optimise_gif(parrot, &limits).unwrap().unwrap();
+
+        let expected = playback(parrot);
+        assert_eq!(gif::Repeat::Infinite, expected.0);
+        assert!(expected.1.len() > 1);
+        assert_eq!(expected, playback(&copied));
+        assert_eq!(expected, playback(&optimised));
+
+        assert!(optimised.len() < copied.len());
+        assert_eq!(
+            optimised,
+            super::reencode_gif(parrot, &limits, true).unwrap()
+        );
+        assert_eq!(copied, super::reencode_gif(parrot, &limits, false).unwrap());
+    }
+
+    #[test]
+    fn optimising_is_limited() {
+        // every frame changes everything, so every patch is the whole picture
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 64, 64, &[]).unwrap();
+            for i in 0..100u8 {
+                let mut frame =
+                    gif::Frame::from_palette_pixels(64, 64, [0; 64 * 64], [i, 0, 0], None);
+                frame.delay = 10;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        let frame_size = 64 * 64 * 4;
+        let roomy = Limits::default();
+        assert!(super::optimise_gif(&gif, &roomy).unwrap().is_some());
+
+        // enough to decode it a frame at a time, but not to hold every patch
+        let limits = Limits {
+            max_alloc: 10 * frame_size,
+            ..Limits::default()
+        };
+        assert!(super::optimise_gif(&gif, &limits).unwrap().is_none());
+        let copied = super::copy_gif(&gif, &limits).unwrap();
+        assert_eq!(copied, super::reencode_gif(&gif, &limits, true).unwrap());
+        assert_eq!(playback(&gif), playback(&copied));
+
+        let animation = super::gif_animation(&gif, &roomy).unwrap().unwrap();
+        assert!(super::encode_gif(&animation, &roomy).unwrap().is_some());
+        assert!(super::encode_gif(&animation, &limits).unwrap().is_none());
+    }
+
+    /// two frames, each with a local palette, the first of which is cleared away
+    fn handmade(repeat: gif::Repeat) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut out, 4, 2, &[]).unwrap();
+            encoder.set_repeat(repeat).unwrap();
+
+            let mut red =
+                gif::Frame::from_palette_pixels(2, 2, [1; 4], [0, 0, 0, 255, 0, 0], Some(0));
+            red.dispose = gif::DisposalMethod::Background;
+            red.delay = 10;
+            encoder.write_frame(&red).unwrap();
+
+            let mut blue = gif::Frame::from_palette_pixels(2, 2, [0; 4], [0, 0, 255], None);
+            blue.left = 2;
+            blue.delay = 20;
+            encoder.write_frame(&blue).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn disposal() {
+        let gif = handmade(gif::Repeat::Finite(3));
+        let (repeat, shown) = playback(&gif);
+        assert_eq!(gif::Repeat::Finite(3), repeat);
+        assert_eq!(2, shown.len());
+
+        let (first, _) = &shown[0];
+        assert_eq!([255, 0, 0, 255], first.get_pixel(0, 0).0);
+        assert_eq!([0; 4], first.get_pixel(3, 0).0);
+
+        let (second, _) = &shown[1];
+        assert_eq!([0; 4], second.get_pixel(0, 0).0);
+        assert_eq!([0, 0, 255, 255], second.get_pixel(3, 1).0);
+
+        let optimised = super::optimise_gif(&gif, &Limits::default()).unwrap();
+        assert_eq!((repeat, shown.clone()), playback(&optimised.unwrap()));
+
+        let copied = super::reencode_gif(&gif, &Limits::default(), true).unwrap();
+        assert_eq!((repeat, shown), playback(&copied));
+    }
+
+    #[test]
+    fn plays_once() {
+        let gif = handmade(gif::Repeat::Finite(0));
+        let copied = super::reencode_gif(&gif, &Limits::default(), true).unwrap();
+        assert_eq!(gif::Repeat::Finite(0), playback(&copied).0);
+    }
+}
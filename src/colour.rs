@@ -0,0 +1,259 @@
+use std::io;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use image::{DynamicImage, ImageBuffer, ImageDecoder};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+use crate::ingest::Limits;
+
+/// Decode an image, and apply any colour profile it has, so it's plain sRGB.
+/// For things we've already stored, so the format is trusted, and there's no orientation;
+/// the limits still apply, in case they've been lowered since it was stored.
+pub fn load_srgb(data: &[u8], limits: &Limits) -> Result<DynamicImage> {
+    let mut reader = image::ImageReader::new(io::Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits.image_limits());
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    limits.check_dimensions(width, height)?;
+    let icc = decoder.icc_profile()?;
+    let image = DynamicImage::from_decoder(decoder)?;
+    Ok(match icc {
+        Some(icc) => to_srgb_or_keep(image, &icc),
+        None => image,
+    })
+}
+
+/// Bad or weird profiles (e.g. for cmyk, which the decoder will have already
+/// guessed at) are ignored, rather than rejecting the image.
+pub fn to_srgb_or_keep(image: DynamicImage, icc: &[u8]) -> DynamicImage {
+    match to_srgb(&image, icc) {
+        Ok(Some(converted)) => converted,
+        Ok(None) => image,
+        Err(e) => {
+            eprintln!("ignoring colour profile: {e:?}");
+            image
+        }
+    }
+}
+
+/// A profile can only be kept if it's for rgb, like the image will be written;
+/// the rest (grey, cmyk, broken) have to be converted, or dropped.
+pub fn embeddable(image: &DynamicImage, icc: &[u8]) -> bool {
+    image.color().channel_count() >= 3
+        && ColorProfile::new_from_slice(icc)
+            .is_ok_and(|profile| profile.color_space == DataColorSpace::Rgb)
+}
+
+/// `None` if the profile isn't for rgb or grey images; grey images come out as rgb
+fn to_srgb(image: &DynamicImage, icc: &[u8]) -> Result<Option<DynamicImage>> {
+    let profile =
+        ColorProfile::new_from_slice(icc).map_err(|e| anyhow!("parsing icc profile: {e:?}"))?;
+    let grey = match profile.color_space {
+        DataColorSpace::Rgb => false,
+        DataColorSpace::Gray => true,
+        _ => return Ok(None),
+    };
+
+    let alpha = image.color().has_alpha();
+    let (src_layout, dst_layout) = match (grey, alpha) {
+        (false, false) => (Layout::Rgb, Layout::Rgb),
+        (false, true) => (Layout::Rgba, Layout::Rgba),
+        (true, false) => (Layout::Gray, Layout::Rgb),
+        (true, true) => (Layout::GrayAlpha, Layout::Rgba),
+    };
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let (width, height) = (image.width(), image.height());
+    let samples = width as usize * height as usize * if alpha { 4 } else { 3 };
+    let wrong_size = || anyhow!("transformed image size mismatch");
+
+    // anything deeper than 8-bit gets 16-bit, so it isn't banded before it's re-encoded
+    let deep = image.color().bytes_per_pixel() > image.color().channel_count();
+    Ok(Some(if deep {
+        let src = match src_layout {
+            Layout::Rgb => image.to_rgb16().into_raw(),
+            Layout::Rgba => image.to_rgba16().into_raw(),
+            Layout::Gray => image.to_luma16().into_raw(),
+            _ => image.to_luma_alpha16().into_raw(),
+        };
+        let mut dst = vec![0u16; samples];
+        profile
+            .create_transform_16bit(src_layout, &srgb, dst_layout, options)
+            .map_err(|e| anyhow!("preparing colour transform: {e:?}"))?
+            .transform(&src, &mut dst)
+            .map_err(|e| anyhow!("transforming colours: {e:?}"))?;
+        if alpha {
+            DynamicImage::ImageRgba16(
+                ImageBuffer::from_raw(width, height, dst).ok_or_else(wrong_size)?,
+            )
+        } else {
+            DynamicImage::ImageRgb16(
+                ImageBuffer::from_raw(width, height, dst).ok_or_else(wrong_size)?,
+            )
+        }
+    } else {
+        let src = match src_layout {
+            Layout::Rgb => image.to_rgb8().into_raw(),
+            Layout::Rgba => image.to_rgba8().into_raw(),
+            Layout::Gray => image.to_luma8().into_raw(),
+            _ => image.to_luma_alpha8().into_raw(),
+        };
+        let mut dst = vec![0u8; samples];
+        profile
+            .create_transform_8bit(src_layout, &srgb, dst_layout, options)
+            .map_err(|e| anyhow!("preparing colour transform: {e:?}"))?
+            .transform(&src, &mut dst)
+            .map_err(|e| anyhow!("transforming colours: {e:?}"))?;
+        if alpha {
+            DynamicImage::ImageRgba8(
+                ImageBuffer::from_raw(width, height, dst).ok_or_else(wrong_size)?,
+            )
+        } else {
+            DynamicImage::ImageRgb8(
+                ImageBuffer::from_raw(width, height, dst).ok_or_else(wrong_size)?,
+            )
+        }
+    }))
+}
+
+/// libwebp only writes simple files, which can't carry a colour profile,
+/// so wrap it in an extended (`VP8X`) file with an `ICCP` chunk.
+pub fn embed_webp_icc(webp: &[u8], icc: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    const ICC_FLAG: u8 = 0x20;
+    const ALPHA_FLAG: u8 = 0x10;
+
+    if webp.len() < 20 || &webp[..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        bail!("not a webp");
+    }
+    let first_chunk = &webp[12..];
+
+    let mut out = Vec::with_capacity(webp.len() + icc.len() + 40);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+
+    let rest = if &first_chunk[..4] == b"VP8X" {
+        // lossy, with alpha; there's already an extended header
+        let (header, rest) = first_chunk.split_at(8 + 10);
+        out.extend_from_slice(header);
+        out[12 + 8] |= ICC_FLAG;
+        rest
+    } else {
+        // the lossless format has a flag for whether alpha is actually used
+        let alpha =
+            &first_chunk[..4] == b"VP8L" && first_chunk.len() > 12 && first_chunk[12] & 0x10 != 0;
+        out.extend_from_slice(b"VP8X");
+        out.extend_from_slice(&10u32.to_le_bytes());
+        out.push(ICC_FLAG | if alpha { ALPHA_FLAG } else { 0 });
+        out.extend_from_slice(&[0; 3]);
+        out.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        out.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        first_chunk
+    };
+
+    out.extend_from_slice(b"ICCP");
+    out.extend_from_slice(
+        &u32::try_from(icc.len())
+            .context("huge icc profile")?
+            .to_le_bytes(),
+    );
+    out.extend_from_slice(icc);
+    if icc.len() % 2 == 1 {
+        out.push(0);
+    }
+
+    out.extend_from_slice(rest);
+    let riff_len = u32::try_from(out.len() - 8).context("huge webp")?;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageDecoder, Rgb, RgbImage};
+    use moxcms::ColorProfile;
+
+    fn p3() -> Vec<u8> {
+        ColorProfile::new_display_p3().encode().unwrap()
+    }
+
+    #[test]
+    fn p3_to_srgb() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 60, 60])));
+
+        let same = super::to_srgb(&image, &ColorProfile::new_srgb().encode().unwrap())
+            .unwrap()
+            .unwrap();
+        let [r, g, b] = same.to_rgb8().get_pixel(0, 0).0;
+        assert!(r.abs_diff(200) <= 1 && g.abs_diff(60) <= 1 && b.abs_diff(60) <= 1);
+
+        // p3's red is redder than srgb's, so it takes more of srgb's red to show it
+        let converted = super::to_srgb(&image, &p3()).unwrap().unwrap();
+        let [r, g, b] = converted.to_rgb8().get_pixel(0, 0).0;
+        assert!(r > 205, "{r}");
+        assert!(g < 55, "{g}");
+        assert!(b < 60, "{b}");
+    }
+
+    #[test]
+    fn grey() {
+        let grey = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+        let image = DynamicImage::ImageLuma8(image::GrayImage::new(2, 2));
+        let converted = super::to_srgb(&image, &grey).unwrap().unwrap();
+        assert_eq!(image::ColorType::Rgb8, converted.color());
+
+        let mut cmyk = ColorProfile::default();
+        cmyk.color_space = moxcms::DataColorSpace::Cmyk;
+        assert!(super::to_srgb(&image, &cmyk.encode().unwrap())
+            .unwrap()
+            .is_none());
+
+        let nonsense = super::to_srgb_or_keep(image.clone(), b"nonsense");
+        assert_eq!(image, nonsense);
+    }
+
+    #[test]
+    fn load_limits() {
+        use crate::ingest::{Limits, Rejected};
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(8, 4))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        assert_eq!(
+            8,
+            super::load_srgb(&png, &Limits::default()).unwrap().width()
+        );
+
+        let limits = Limits {
+            max_pixels: 16,
+            ..Limits::default()
+        };
+        let err = super::load_srgb(&png, &limits).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Rejected::TooLarge(_))));
+    }
+
+    #[test]
+    fn webp_icc() {
+        let icc = p3();
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            3,
+            2,
+            image::Rgba([1, 2, 3, 128]),
+        ));
+        for lossless in [true, false] {
+            let webp = webp::Encoder::from_rgba(image.as_bytes(), 3, 2)
+                .encode_simple(lossless, 75.)
+                .unwrap();
+            let embedded = super::embed_webp_icc(&webp, &icc, 3, 2).unwrap();
+
+            let mut decoder =
+                image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(&embedded)).unwrap();
+            assert_eq!(Some(icc.clone()), decoder.icc_profile().unwrap());
+            assert_eq!((3, 2), decoder.dimensions());
+            let decoded = DynamicImage::from_decoder(decoder).unwrap();
+            assert!(decoded.color().has_alpha());
+        }
+    }
+}
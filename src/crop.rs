@@ -0,0 +1,196 @@
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+/// the busyness is only needed roughly, so it's measured on a copy about this big
+const SCORE_SIZE: u32 = 256;
+
+/// A part of an image, in its pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Shrink and crop the image to exactly the shape of `width` x `height`, keeping
+/// the busiest part of it (e.g. the text at the top of a long screenshot),
+/// instead of letterboxing it. It's never enlarged.
+pub fn smart_crop(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let window = busiest_window(image, width, height);
+    crop_to(image, window, width, height)
+}
+
+/// cut out a window picked by `busiest_window`, then shrink it to the size it was picked for
+pub fn crop_to(image: &DynamicImage, window: Window, width: u32, height: u32) -> DynamicImage {
+    let cropped = image.crop_imm(window.x, window.y, window.width, window.height);
+    if window.width <= width {
+        cropped
+    } else {
+        cropped.thumbnail_exact(width, height)
+    }
+}
+
+/// The biggest window of the shape of `width` x `height` which fits in the image,
+/// slid along to where there are the most edges. Flat images get the middle.
+pub fn busiest_window(image: &DynamicImage, width: u32, height: u32) -> Window {
+    let (image_width, image_height) = image.dimensions();
+    let wider =
+        u64::from(image_width) * u64::from(height) > u64::from(image_height) * u64::from(width);
+    let (window_width, window_height) = if wider {
+        let w = u64::from(image_height) * u64::from(width) / u64::from(height);
+        ((w as u32).max(1), image_height)
+    } else {
+        let h = u64::from(image_width) * u64::from(height) / u64::from(width);
+        (image_width, (h as u32).max(1))
+    };
+
+    let full = Window {
+        x: 0,
+        y: 0,
+        width: window_width,
+        height: window_height,
+    };
+    if (window_width, window_height) == (image_width, image_height) {
+        return full;
+    }
+
+    let small = image.thumbnail(SCORE_SIZE, SCORE_SIZE).to_luma8();
+    let (profile, scale, length, free) = if wider {
+        let scale = f64::from(small.width()) / f64::from(image_width);
+        (column_edges(&small), scale, window_width, image_width)
+    } else {
+        let scale = f64::from(small.height()) / f64::from(image_height);
+        (row_edges(&small), scale, window_height, image_height)
+    };
+
+    let small_length = ((f64::from(length) * scale).round() as usize).clamp(1, profile.len());
+    let small_offset = best_offset(&profile, small_length);
+    let offset = ((small_offset as f64 / scale).round() as u32).min(free - length);
+
+    if wider {
+        Window { x: offset, ..full }
+    } else {
+        Window { y: offset, ..full }
+    }
+}
+
+/// how much each pixel differs from its neighbours to the right and below
+fn edges(image: &GrayImage) -> impl Iterator<Item = (u32, u32, u64)> + '_ {
+    image.enumerate_pixels().map(|(x, y, p)| {
+        let here = i32::from(p[0]);
+        let diff = |x, y| {
+            if x < image.width() && y < image.height() {
+                (here - i32::from(image.get_pixel(x, y)[0])).unsigned_abs()
+            } else {
+                0
+            }
+        };
+        (x, y, u64::from(diff(x + 1, y) + diff(x, y + 1)))
+    })
+}
+
+fn column_edges(image: &GrayImage) -> Vec<u64> {
+    let mut columns = vec![0; image.width() as usize];
+    for (x, _, edge) in edges(image) {
+        columns[x as usize] += edge;
+    }
+    columns
+}
+
+fn row_edges(image: &GrayImage) -> Vec<u64> {
+    let mut rows = vec![0; image.height() as usize];
+    for (_, y, edge) in edges(image) {
+        rows[y as usize] += edge;
+    }
+    rows
+}
+
+/// Where a run of `length` has the highest total. There are often several, e.g. the
+/// whole of a busy bit fits in the window in a few places, so pick the middle one.
+fn best_offset(profile: &[u64], length: usize) -> usize {
+    let mut sum: u64 = profile[..length].iter().sum();
+    let mut sums = Vec::with_capacity(profile.len() - length + 1);
+    sums.push(sum);
+    for offset in 1..=profile.len() - length {
+        sum = sum + profile[offset + length - 1] - profile[offset - 1];
+        sums.push(sum);
+    }
+
+    let max = sums.iter().copied().max().expect("at least one");
+    let best = || sums.iter().enumerate().filter(|&(_, &sum)| sum == max);
+    let first = best().next().expect("the max").0;
+    let last = best().next_back().expect("the max").0;
+    let middle = (first + last) / 2;
+    best()
+        .map(|(offset, _)| offset)
+        .min_by_key(|offset| offset.abs_diff(middle))
+        .expect("the max")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::Window;
+
+    /// mostly white, with some stripes (like text) in one place
+    fn busy_at(width: u32, height: u32, x: u32, y: u32) -> DynamicImage {
+        let mut image = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+        for dy in 0..100 {
+            for dx in 0..100 {
+                if dy % 4 < 2 {
+                    image.put_pixel(x + dx, y + dy, Rgb([0, 0, 0]));
+                }
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn windows() {
+        // a tall screenshot, with the interesting bit at the top
+        let tall = busy_at(400, 3000, 150, 20);
+        let window = super::busiest_window(&tall, 320, 160);
+        assert_eq!((0, 400, 200), (window.x, window.width, window.height));
+        assert!(window.y <= 20, "{window:?}");
+
+        // and at the bottom
+        let tall = busy_at(400, 3000, 150, 2850);
+        let window = super::busiest_window(&tall, 320, 160);
+        assert!(window.y + window.height >= 2950, "{window:?}");
+
+        let wide = busy_at(2000, 200, 1500, 50);
+        let window = super::busiest_window(&wide, 100, 100);
+        assert_eq!((0, 200, 200), (window.y, window.width, window.height));
+        assert!(window.x <= 1500 && window.x + 200 >= 1600, "{window:?}");
+
+        // the middle, give or take the rounding from scoring it smaller
+        let flat = DynamicImage::ImageRgb8(RgbImage::new(1000, 100));
+        let window = super::busiest_window(&flat, 50, 50);
+        assert_eq!((0, 100, 100), (window.y, window.width, window.height));
+        assert!(window.x.abs_diff(450) <= 4, "{window:?}");
+
+        let right_shape = DynamicImage::ImageRgb8(RgbImage::new(640, 320));
+        assert_eq!(
+            Window {
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 320
+            },
+            super::busiest_window(&right_shape, 320, 160)
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let tall = busy_at(400, 3000, 150, 20);
+        let cropped = super::smart_crop(&tall, 320, 160);
+        assert_eq!((320, 160), (cropped.width(), cropped.height()));
+
+        // the right shape, but no bigger than the original
+        let small = busy_at(100, 1000, 0, 0);
+        let cropped = super::smart_crop(&small, 320, 160);
+        assert_eq!((100, 50), (cropped.width(), cropped.height()));
+    }
+}
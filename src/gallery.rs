@@ -22,24 +22,132 @@ added datetime not null
 on gallery_images (gallery, image)",
         [],
     )?;
+
+    // lowest first; galleries from before this are ordered as they were, newest first
+    crate::images::add_column_if_missing(conn, "gallery_images", "position", "integer")?;
+    conn.execute(
+        "update gallery_images set position=(
+select count(*) from gallery_images newer
+where newer.gallery=gallery_images.gallery and newer.added > gallery_images.added
+) where position is null",
+        [],
+    )?;
+
+    crate::images::add_column_if_missing(conn, "gallery_images", "caption", "varchar")?;
+    crate::images::add_column_if_missing(conn, "gallery_images", "alt", "varchar")?;
+
+    // keyed by the public id; the private part is never stored
+    conn.execute(
+        "create table if not exists galleries (
+public varchar primary key not null,
+title varchar,
+description varchar,
+cover varchar,
+created datetime not null,
+updated datetime not null
+)",
+        [],
+    )?;
+    conn.execute(
+        "insert into galleries (public, created, updated)
+select gallery, min(added), max(added) from gallery_images group by gallery
+on conflict (public) do nothing",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Everything about a gallery, other than what's in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GalleryInfo {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// always one of the gallery's images
+    pub cover: Option<String>,
+    pub created: i64,
+    pub updated: i64,
+}
+
+/// `None` if nothing has ever been put in the gallery
+pub fn gallery_info(conn: &Connection, public: &str) -> Result<Option<GalleryInfo>, Error> {
+    let mut stat = conn.prepare(
+        "select title, description, cover, created, updated from galleries where public=?",
+    )?;
+    let mut rows = stat.query([public])?;
+    let row = match rows.next()? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    Ok(Some(GalleryInfo {
+        title: row.get(0)?,
+        description: row.get(1)?,
+        cover: row.get(2)?,
+        created: row.get(3)?,
+        updated: row.get(4)?,
+    }))
+}
+
+/// note that the gallery has changed, creating it if it's new
+fn touch(conn: &Connection, public: &str, now: i64) -> Result<(), Error> {
+    conn.execute(
+        "insert into galleries (public, created, updated) values (?1, ?2, ?2)
+on conflict (public) do update set updated=?2",
+        rusqlite::params![public, now],
+    )?;
+    Ok(())
+}
+
+/// the cover has to be in the gallery, so it goes when it's taken out
+fn forget_missing_cover(conn: &Connection) -> Result<(), Error> {
+    conn.execute(
+        "update galleries set cover=null where cover is not null and not exists (
+select 1 from gallery_images where gallery=galleries.public and image=galleries.cover
+)",
+        [],
+    )?;
     Ok(())
 }
 
-pub fn gallery_list_all(conn: &Connection, public: &str) -> Result<Vec<String>, Error> {
+/// longer than anyone should be typing into a caption
+pub const MAX_TEXT_LEN: usize = 2000;
+
+/// An image, as it appears in a gallery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GalleryImage {
+    pub id: String,
+    pub caption: Option<String>,
+    /// a description of the image, for people who can't see it
+    pub alt: Option<String>,
+}
+
+pub fn gallery_list_all(conn: &Connection, public: &str) -> Result<Vec<GalleryImage>, Error> {
     let mut stat = conn.prepare(
-        "select image from gallery_images
-where gallery=? order by added desc",
+        "select image, caption, alt from gallery_images
+where gallery=? order by position, added desc",
     )?;
 
     let mut resp = Vec::new();
 
-    for image in stat.query_map([public], |row| row.get::<usize, String>(0))? {
+    for image in stat.query_map([public], |row| {
+        Ok(GalleryImage {
+            id: row.get(0)?,
+            caption: row.get(1)?,
+            alt: row.get(2)?,
+        })
+    })? {
         resp.push(image?);
     }
 
     Ok(resp)
 }
 
+/// remove an image from every gallery it's in
+pub fn gallery_forget_image(conn: &Connection, image: &str) -> Result<(), Error> {
+    conn.execute("delete from gallery_images where image=?", [image])?;
+    forget_missing_cover(conn)?;
+    Ok(())
+}
+
 pub fn gallery_store(
     conn: &Arc<Mutex<Connection>>,
     global_secret: &[u8],
@@ -50,14 +158,17 @@ pub fn gallery_store(
     let public = public_id_for(global_secret, gallery, private);
 
     let conn = conn.lock().map_err(|_| anyhow!("poison"))?;
-    let mut stat =
-        conn.prepare("insert into gallery_images (gallery, image, added) values (?, ?, ?)")?;
+    // new images go in front of everything else
+    let mut stat = conn.prepare(
+        "insert into gallery_images (gallery, image, added, position)
+values (?1, ?2, ?3, (select coalesce(min(position), 0) - 1 from gallery_images where gallery=?1))",
+    )?;
 
-    let mut timestamp = epoch_millis();
+    let timestamp = epoch_millis();
 
     for image in images {
         match stat.execute([&public.as_str() as &dyn ToSql, &image, &timestamp]) {
-            Ok(_) => timestamp += 1,
+            Ok(_) => (),
             Err(rusqlite::Error::SqliteFailure(ffi, _))
                 if rusqlite::ErrorCode::ConstraintViolation == ffi.code =>
             {
@@ -66,10 +177,173 @@ pub fn gallery_store(
             Err(e) => bail!(e),
         }
     }
+    touch(&conn, &public, timestamp)?;
 
     Ok(public)
 }
 
+/// Take images out of a gallery, if they're in it; returns the gallery's public id,
+/// and how many were actually removed.
+pub fn gallery_remove(
+    conn: &Arc<Mutex<Connection>>,
+    global_secret: &[u8],
+    gallery: &str,
+    private: &str,
+    images: &[&str],
+) -> Result<(String, usize), Error> {
+    let public = public_id_for(global_secret, gallery, private);
+
+    let mut conn = conn.lock().map_err(|_| anyhow!("poison"))?;
+    let tx = conn.transaction()?;
+    let mut removed = 0;
+    {
+        let mut stat = tx.prepare("delete from gallery_images where gallery=? and image=?")?;
+        for image in images {
+            removed += stat.execute([public.as_str(), image])?;
+        }
+    }
+    if removed > 0 {
+        touch(&tx, &public, epoch_millis())?;
+        forget_missing_cover(&tx)?;
+    }
+    tx.commit()?;
+
+    Ok((public, removed))
+}
+
+/// Where to move an image to, in a gallery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Move {
+    /// counting from zero; past the end is the end
+    To(usize),
+    Before(String),
+    After(String),
+}
+
+/// Changes to a gallery, and to one of its images; `None`s are left alone,
+/// and empty strings clear things.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Patch<'a> {
+    pub image: Option<&'a str>,
+    pub to: Option<Move>,
+    pub caption: Option<&'a str>,
+    pub alt: Option<&'a str>,
+    pub title: Option<&'a str>,
+    pub description: Option<&'a str>,
+    /// has to be in the gallery
+    pub cover: Option<&'a str>,
+}
+
+/// What a patch needed, but wasn't there; nothing is changed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Missing {
+    /// there's no gallery with that password, or it's empty
+    Gallery,
+    Image,
+    /// the image being moved next to
+    Target,
+    Cover,
+}
+
+/// Make all of the changes, or none of them. Returns the gallery's public id.
+pub fn gallery_patch(
+    conn: &Arc<Mutex<Connection>>,
+    global_secret: &[u8],
+    gallery: &str,
+    private: &str,
+    patch: &Patch,
+) -> Result<Result<String, Missing>, Error> {
+    let public = public_id_for(global_secret, gallery, private);
+
+    let mut conn = conn.lock().map_err(|_| anyhow!("poison"))?;
+    let tx = conn.transaction()?;
+
+    let mut order = gallery_list_all(&tx, &public)?
+        .into_iter()
+        .map(|image| image.id)
+        .collect::<Vec<_>>();
+    if order.is_empty() {
+        return Ok(Err(Missing::Gallery));
+    }
+    if let Some(cover) = patch.cover.filter(|cover| !cover.is_empty()) {
+        if !order.iter().any(|image| image == cover) {
+            return Ok(Err(Missing::Cover));
+        }
+    }
+    if let Some(image) = patch.image {
+        let Some(from) = order.iter().position(|other| other == image) else {
+            return Ok(Err(Missing::Image));
+        };
+        if let Some(to) = &patch.to {
+            let moving = order.remove(from);
+            let index = match to {
+                Move::To(index) => (*index).min(order.len()),
+                Move::Before(other) | Move::After(other) => {
+                    let Some(index) = order.iter().position(|o| o == other) else {
+                        return Ok(Err(Missing::Target));
+                    };
+                    match to {
+                        Move::After(_) => index + 1,
+                        _ => index,
+                    }
+                }
+            };
+            order.insert(index, moving);
+        }
+    }
+
+    // everything's been checked, so it can all be done
+    if let Some(image) = patch.image {
+        if patch.to.is_some() {
+            // galleries are small enough to just renumber everything
+            let mut stat =
+                tx.prepare("update gallery_images set position=? where gallery=? and image=?")?;
+            for (position, image) in order.iter().enumerate() {
+                stat.execute([&(position as i64) as &dyn ToSql, &public, image])?;
+            }
+        }
+        if patch.caption.is_some() || patch.alt.is_some() {
+            tx.execute(
+                "update gallery_images set
+caption=case when ?1 then nullif(?2, '') else caption end,
+alt=case when ?3 then nullif(?4, '') else alt end
+where gallery=?5 and image=?6",
+                rusqlite::params![
+                    patch.caption.is_some(),
+                    patch.caption,
+                    patch.alt.is_some(),
+                    patch.alt,
+                    public,
+                    image
+                ],
+            )?;
+        }
+    }
+
+    touch(&tx, &public, epoch_millis())?;
+    if patch.title.is_some() || patch.description.is_some() || patch.cover.is_some() {
+        tx.execute(
+            "update galleries set
+title=case when ?1 then nullif(?2, '') else title end,
+description=case when ?3 then nullif(?4, '') else description end,
+cover=case when ?5 then nullif(?6, '') else cover end
+where public=?7",
+            rusqlite::params![
+                patch.title.is_some(),
+                patch.title,
+                patch.description.is_some(),
+                patch.description,
+                patch.cover.is_some(),
+                patch.cover,
+                public
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(Ok(public))
+}
+
 fn public_id_for(global_secret: &[u8], gallery: &str, private: &str) -> String {
     let user_details = mac(gallery.as_bytes(), private.as_bytes());
     let masked = mac(global_secret, &user_details);
@@ -81,14 +355,24 @@ fn public_id_for(global_secret: &[u8], gallery: &str, private: &str) -> String {
     public
 }
 
-fn mac(key: &[u8], val: &[u8]) -> Vec<u8> {
+pub fn mac(key: &[u8], val: &[u8]) -> Vec<u8> {
     use hmac::{KeyInit, Mac};
     let mut mac = hmac::Hmac::<sha2::Sha512_256>::new_from_slice(key).expect("invalid key len");
     mac.update(val);
     mac.finalize().into_bytes().to_vec()
 }
 
-fn epoch_millis() -> i64 {
+pub fn tokens_equal(expected: &str, actual: &str) -> bool {
+    // not that anyone's timing us, but
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |acc, (l, r)| acc | (l ^ r))
+            == 0
+}
+
+pub fn epoch_millis() -> i64 {
     use std::time;
     let start = time::SystemTime::now();
     let since_the_epoch = start
@@ -106,10 +390,19 @@ mod tests {
 
     use anyhow::Result;
 
+    /// how an image is listed before it's been described
+    fn plain(id: &str) -> super::GalleryImage {
+        super::GalleryImage {
+            id: id.to_string(),
+            caption: None,
+            alt: None,
+        }
+    }
+
     #[test]
     fn mem_db() -> Result<()> {
-        let mut conn = rusqlite::Connection::open_in_memory()?;
-        super::migrate_gallery(&mut conn)?;
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
         let wrapped = Arc::new(Mutex::new(conn));
         let public = super::gallery_store(
             &wrapped.clone(),
@@ -119,9 +412,308 @@ mod tests {
             &["e/img.jpg", "e/two.jpg"],
         )?;
         assert_eq!(
-            vec!["e/two.jpg", "e/img.jpg"],
-            super::gallery_list_all(&mut wrapped.lock().unwrap(), &public)?
+            vec![plain("e/two.jpg"), plain("e/img.jpg")],
+            super::gallery_list_all(&wrapped.lock().unwrap(), &public)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reorder() -> Result<()> {
+        use super::{Missing, Move, Patch};
+
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
+        let wrapped = Arc::new(Mutex::new(conn));
+        let public = super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/a.jpg", "e/b.jpg"])?;
+        super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/c.jpg", "e/d.jpg"])?;
+        let list = || -> Result<Vec<_>> {
+            Ok(super::gallery_list_all(&wrapped.lock().unwrap(), &public)?
+                .into_iter()
+                .map(|image| image.id)
+                .collect())
+        };
+        assert_eq!(vec!["e/d.jpg", "e/c.jpg", "e/b.jpg", "e/a.jpg"], list()?);
+
+        let mv = |image: &str, to: Move| {
+            let patch = Patch {
+                image: Some(image),
+                to: Some(to),
+                ..Patch::default()
+            };
+            super::gallery_patch(&wrapped, &[1], "foo", "bar", &patch)
+        };
+        assert_eq!(Ok(public.clone()), mv("e/d.jpg", Move::To(2))?);
+        assert_eq!(vec!["e/c.jpg", "e/b.jpg", "e/d.jpg", "e/a.jpg"], list()?);
+        mv("e/c.jpg", Move::To(99))?.expect("patched");
+        assert_eq!(vec!["e/b.jpg", "e/d.jpg", "e/a.jpg", "e/c.jpg"], list()?);
+        mv("e/a.jpg", Move::Before("e/b.jpg".to_string()))?.expect("patched");
+        assert_eq!(vec!["e/a.jpg", "e/b.jpg", "e/d.jpg", "e/c.jpg"], list()?);
+        mv("e/b.jpg", Move::After("e/c.jpg".to_string()))?.expect("patched");
+        assert_eq!(vec!["e/a.jpg", "e/d.jpg", "e/c.jpg", "e/b.jpg"], list()?);
+
+        assert_eq!(Err(Missing::Image), mv("e/nope.jpg", Move::To(0))?);
+        assert_eq!(
+            Err(Missing::Target),
+            mv("e/a.jpg", Move::After("e/nope.jpg".to_string()))?
+        );
+        // it can't be moved next to itself
+        assert_eq!(
+            Err(Missing::Target),
+            mv("e/a.jpg", Move::After("e/a.jpg".to_string()))?
+        );
+
+        // new images still go in front
+        super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/e.jpg"])?;
+        assert_eq!("e/e.jpg", list()?[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn describe() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
+        let wrapped = Arc::new(Mutex::new(conn));
+        let public = super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/a.jpg", "e/b.jpg"])?;
+        let describe = |image, caption, alt| {
+            let patch = super::Patch {
+                image: Some(image),
+                caption,
+                alt,
+                ..super::Patch::default()
+            };
+            super::gallery_patch(&wrapped, &[1], "foo", "bar", &patch)
+        };
+        let described = |image| -> Result<_> {
+            let images = super::gallery_list_all(&wrapped.lock().unwrap(), &public)?;
+            let image = images.into_iter().find(|i| i.id == image).expect("listed");
+            Ok((image.caption, image.alt))
+        };
+        assert_eq!((None, None), described("e/a.jpg")?);
+
+        assert_eq!(
+            Ok(public.clone()),
+            describe("e/a.jpg", Some("the first"), Some("a cat"))?
+        );
+        assert_eq!(
+            (Some("the first".to_string()), Some("a cat".to_string())),
+            described("e/a.jpg")?
+        );
+        assert_eq!((None, None), described("e/b.jpg")?);
+
+        // only what's given is changed, and empty clears
+        describe("e/a.jpg", None, Some("a sleeping cat"))?.expect("patched");
+        assert_eq!(
+            (
+                Some("the first".to_string()),
+                Some("a sleeping cat".to_string())
+            ),
+            described("e/a.jpg")?
+        );
+        describe("e/a.jpg", Some(""), None)?.expect("patched");
+        assert_eq!(
+            (None, Some("a sleeping cat".to_string())),
+            described("e/a.jpg")?
+        );
+
+        assert_eq!(
+            Err(super::Missing::Image),
+            describe("e/nope.jpg", Some("hi"), None)?
+        );
+        // the wrong password is a different gallery
+        let patch = super::Patch {
+            image: Some("e/a.jpg"),
+            caption: Some("hi"),
+            ..super::Patch::default()
+        };
+        assert_eq!(
+            Err(super::Missing::Gallery),
+            super::gallery_patch(&wrapped, &[1], "foo", "baz", &patch)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn info() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
+        let wrapped = Arc::new(Mutex::new(conn));
+        let info = |public: &str| super::gallery_info(&wrapped.lock().unwrap(), public);
+        let set = |title, description, cover| {
+            let patch = super::Patch {
+                title,
+                description,
+                cover,
+                ..super::Patch::default()
+            };
+            super::gallery_patch(&wrapped, &[1], "foo", "bar", &patch)
+        };
+
+        // nothing to describe yet
+        assert_eq!(Err(super::Missing::Gallery), set(Some("cats"), None, None)?);
+        let public = super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/a.jpg", "e/b.jpg"])?;
+        let created = info(&public)?.expect("stored");
+        assert_eq!(
+            (None, None, None),
+            (created.title, created.description, created.cover)
+        );
+        assert_eq!(created.created, created.updated);
+
+        assert_eq!(
+            Ok(public.clone()),
+            set(Some("cats"), Some("sleeping"), Some("e/b.jpg"))?
+        );
+        let described = info(&public)?.expect("stored");
+        assert_eq!(Some("cats".to_string()), described.title);
+        assert_eq!(Some("sleeping".to_string()), described.description);
+        assert_eq!(Some("e/b.jpg".to_string()), described.cover);
+        assert_eq!(created.created, described.created);
+        assert!(described.updated >= created.updated);
+
+        // the cover has to be in the gallery
+        assert_eq!(
+            Err(super::Missing::Cover),
+            set(None, None, Some("e/nope.jpg"))?
+        );
+        set(None, Some(""), None)?.expect("patched");
+        let cleared = info(&public)?.expect("stored");
+        assert_eq!(
+            (Some("cats".to_string()), None, Some("e/b.jpg".to_string())),
+            (cleared.title, cleared.description, cleared.cover)
+        );
+
+        super::gallery_remove(&wrapped, &[1], "foo", "bar", &["e/b.jpg"])?;
+        assert_eq!(None, info(&public)?.expect("stored").cover);
+
+        set(None, None, Some("e/a.jpg"))?.expect("patched");
+        super::gallery_forget_image(&wrapped.lock().unwrap(), "e/a.jpg")?;
+        assert_eq!(None, info(&public)?.expect("stored").cover);
+
+        assert_eq!(None, info("foo:nope")?);
+        Ok(())
+    }
+
+    #[test]
+    fn all_or_nothing() -> Result<()> {
+        use super::{Move, Patch};
+
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
+        let wrapped = Arc::new(Mutex::new(conn));
+        let public = super::gallery_store(&wrapped, &[1], "foo", "bar", &["e/a.jpg", "e/b.jpg"])?;
+        let before = super::gallery_info(&wrapped.lock().unwrap(), &public)?;
+
+        // the move can't happen, so neither can anything else
+        let patch = Patch {
+            image: Some("e/a.jpg"),
+            to: Some(Move::After("e/nope.jpg".to_string())),
+            caption: Some("hi"),
+            title: Some("cats"),
+            cover: Some("e/a.jpg"),
+            ..Patch::default()
+        };
+        assert_eq!(
+            Err(super::Missing::Target),
+            super::gallery_patch(&wrapped, &[1], "foo", "bar", &patch)?
+        );
+        assert_eq!(
+            before,
+            super::gallery_info(&wrapped.lock().unwrap(), &public)?
+        );
+        let images = super::gallery_list_all(&wrapped.lock().unwrap(), &public)?;
+        assert_eq!(vec![plain("e/b.jpg"), plain("e/a.jpg")], images);
+
+        let patch = Patch {
+            to: Some(Move::To(0)),
+            ..patch
+        };
+        assert_eq!(
+            Ok(public.clone()),
+            super::gallery_patch(&wrapped, &[1], "foo", "bar", &patch)?
+        );
+        let images = super::gallery_list_all(&wrapped.lock().unwrap(), &public)?;
+        let captioned = super::GalleryImage {
+            caption: Some("hi".to_string()),
+            ..plain("e/a.jpg")
+        };
+        assert_eq!(vec![captioned, plain("e/b.jpg")], images);
+        let info = super::gallery_info(&wrapped.lock().unwrap(), &public)?.expect("stored");
+        assert_eq!(
+            (Some("cats".to_string()), Some("e/a.jpg".to_string())),
+            (info.title, info.cover)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_position() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        conn.execute(
+            "create table gallery_images (
+gallery char(10) not null,
+image char(15) not null,
+added datetime not null
+)",
+            [],
+        )?;
+        for (gallery, image, added) in [
+            ("foo:a", "e/old.jpg", 1),
+            ("foo:a", "e/new.jpg", 3),
+            ("foo:a", "e/mid.jpg", 2),
+            ("foo:b", "e/one.jpg", 5),
+        ] {
+            conn.execute(
+                "insert into gallery_images (gallery, image, added) values (?, ?, ?)",
+                rusqlite::params![gallery, image, added],
+            )?;
+        }
+        super::migrate_gallery(&conn)?;
+        super::migrate_gallery(&conn)?;
+        assert_eq!(
+            vec![plain("e/new.jpg"), plain("e/mid.jpg"), plain("e/old.jpg")],
+            super::gallery_list_all(&conn, "foo:a")?
+        );
+        assert_eq!(
+            vec![plain("e/one.jpg")],
+            super::gallery_list_all(&conn, "foo:b")?
+        );
+
+        let info = super::gallery_info(&conn, "foo:a")?.expect("backfilled");
+        assert_eq!((None, 1, 3), (info.title, info.created, info.updated));
+        Ok(())
+    }
+
+    #[test]
+    fn remove() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_gallery(&conn)?;
+        let wrapped = Arc::new(Mutex::new(conn));
+        let images = ["e/img.jpg", "e/two.jpg", "e/three.jpg"];
+        let public = super::gallery_store(&wrapped, &[1], "foo", "bar", &images)?;
+        let other = super::gallery_store(&wrapped, &[1], "foo", "baz", &images)?;
+
+        // the wrong password is a different gallery, which this isn't in
+        assert_eq!(
+            (other.clone(), 0),
+            super::gallery_remove(&wrapped, &[1], "foo", "baz", &["e/nope.jpg"])?
+        );
+        assert_eq!(
+            (public.clone(), 2),
+            super::gallery_remove(
+                &wrapped,
+                &[1],
+                "foo",
+                "bar",
+                &["e/img.jpg", "e/three.jpg", "e/nope.jpg"]
+            )?
+        );
+
+        let conn = wrapped.lock().unwrap();
+        assert_eq!(
+            vec![plain("e/two.jpg")],
+            super::gallery_list_all(&conn, &public)?
         );
+        assert_eq!(3, super::gallery_list_all(&conn, &other)?.len());
         Ok(())
     }
 
@@ -0,0 +1,518 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use base64::Engine;
+use rand::Rng as _;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+use crate::storage::StorageConfig;
+use crate::thumbs::ThumbConfig;
+
+pub fn migrate_images(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "create table if not exists images (
+id varchar primary key not null,
+original_format varchar not null,
+format varchar not null,
+width integer not null,
+height integer not null,
+size integer not null,
+sha256 char(64) not null,
+uploaded datetime not null,
+caller_addr varchar not null,
+caller_forwarded varchar
+)",
+        [],
+    )?;
+    add_column_if_missing(conn, "images", "input_sha256", "char(64)")?;
+    add_column_if_missing(conn, "images", "expires", "datetime")?;
+    add_column_if_missing(conn, "images", "blurhash", "varchar")?;
+    conn.execute(
+        "create index if not exists images_sha256 on images (sha256)",
+        [],
+    )?;
+    conn.execute(
+        "create index if not exists images_input_sha256 on images (input_sha256)",
+        [],
+    )?;
+    conn.execute(
+        "create table if not exists uploads (
+token_sha256 char(64) primary key not null,
+image varchar not null,
+uploaded datetime not null
+)",
+        [],
+    )?;
+    conn.execute(
+        "create index if not exists uploads_image on uploads (image)",
+        [],
+    )?;
+    // so they can all be found again, even if the thumbnail presets change
+    conn.execute(
+        "create table if not exists derivatives (
+image varchar not null,
+name varchar not null,
+primary key (image, name)
+)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// sqlite has no `add column if not exists`
+pub fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<()> {
+    let mut stat = conn.prepare("select name from pragma_table_info(?)")?;
+    for name in stat.query_map([table], |row| row.get::<usize, String>(0))? {
+        if name? == column {
+            return Ok(());
+        }
+    }
+    conn.execute(
+        &format!("alter table {table} add column {column} {decl}"),
+        [],
+    )?;
+    Ok(())
+}
+
+/// who sent us an image, as far as we can tell
+#[derive(Clone, Debug)]
+pub struct Uploader {
+    pub addr: String,
+    pub forwarded_for: Option<String>,
+}
+
+/// everything we know about a stored image; formats are mime types
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageRecord {
+    pub id: String,
+    pub original_format: String,
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub size: i64,
+    pub sha256: String,
+    /// only recorded when deduplicating on input
+    pub input_sha256: Option<String>,
+    pub uploaded: i64,
+    /// millis since the epoch, after which the image will be removed
+    pub expires: Option<i64>,
+    /// a tiny, blurry version of the image, for placeholders; missing for older images
+    pub blurhash: Option<String>,
+    pub caller_addr: String,
+    pub caller_forwarded: Option<String>,
+}
+
+pub fn record(conn: &Connection, image: &ImageRecord) -> Result<()> {
+    conn.execute(
+        "insert into images (id, original_format, format, width, height, size, sha256,
+input_sha256, uploaded, expires, blurhash, caller_addr, caller_forwarded)
+values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            image.id,
+            image.original_format,
+            image.format,
+            image.width,
+            image.height,
+            image.size,
+            image.sha256,
+            image.input_sha256,
+            image.uploaded,
+            image.expires,
+            image.blurhash,
+            image.caller_addr,
+            image.caller_forwarded,
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn get(conn: &Connection, id: &str) -> Result<Option<ImageRecord>> {
+    let mut stat = conn.prepare(
+        "select id, original_format, format, width, height, size, sha256,
+input_sha256, uploaded, expires, blurhash, caller_addr, caller_forwarded from images where id=?",
+    )?;
+
+    let mut rows = stat.query([id])?;
+    let row = match rows.next()? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    Ok(Some(ImageRecord {
+        id: row.get(0)?,
+        original_format: row.get(1)?,
+        format: row.get(2)?,
+        width: row.get(3)?,
+        height: row.get(4)?,
+        size: row.get(5)?,
+        sha256: row.get(6)?,
+        input_sha256: row.get(7)?,
+        uploaded: row.get(8)?,
+        expires: row.get(9)?,
+        blurhash: row.get(10)?,
+        caller_addr: row.get(11)?,
+        caller_forwarded: row.get(12)?,
+    }))
+}
+
+/// the image's placeholder, if it has one
+pub fn blurhash(conn: &Connection, id: &str) -> Result<Option<String>> {
+    let mut stat = conn.prepare("select blurhash from images where id=?")?;
+    let mut rows = stat.query([id])?;
+    Ok(match rows.next()? {
+        Some(row) => row.get(0)?,
+        None => None,
+    })
+}
+
+/// previously stored images whose stored bytes hash to this, newest first
+pub fn with_sha256(conn: &Connection, sha256: &str) -> Result<Vec<String>> {
+    let mut stat = conn.prepare("select id from images where sha256=? order by uploaded desc")?;
+    let ids = stat.query_map([sha256], |row| row.get::<usize, String>(0))?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+/// previously stored images which were uploaded as exactly these bytes, newest first
+pub fn with_input_sha256(conn: &Connection, input_sha256: &str) -> Result<Vec<String>> {
+    let mut stat =
+        conn.prepare("select id from images where input_sha256=? order by uploaded desc")?;
+    let ids = stat.query_map([input_sha256], |row| row.get::<usize, String>(0))?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+/// Someone else has uploaded an image which is already stored, so it must live at least
+/// as long as they asked for, which might be forever (`None`).
+pub fn extend_expiry(conn: &Connection, id: &str, expires: Option<i64>) -> Result<()> {
+    conn.execute(
+        "update images set expires=case
+when expires is null or ?1 is null then null
+else max(expires, ?1) end
+where id=?2",
+        params![expires, id],
+    )?;
+    Ok(())
+}
+
+/// images which should have been removed by `now`
+pub fn expired(conn: &Connection, now: i64) -> Result<Vec<String>> {
+    let mut stat = conn.prepare("select id from images where expires <= ?")?;
+    let ids = stat.query_map([now], |row| row.get::<usize, String>(0))?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+/// A secret which lets whoever uploaded an image delete it. Every upload gets its own,
+/// as a deduplicated image can have several uploaders; only its hash is kept.
+pub fn issue_delete_token(conn: &Connection, image_id: &str, now: i64) -> Result<String> {
+    let mut buf = [0u8; 18];
+    rand::rng().fill_bytes(&mut buf);
+    let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(buf);
+    conn.execute(
+        "insert into uploads (token_sha256, image, uploaded) values (?, ?, ?)",
+        params![sha256_hex(token.as_bytes()), image_id, now],
+    )?;
+    Ok(token)
+}
+
+/// Tokens used to be derived from the image id, rather than stored, so images from then
+/// have no uploads: give them one, for the token they were given, so it still works.
+/// As before, everyone who uploaded one of those shares its token.
+pub fn backfill_delete_tokens(conn: &Connection, global_secret: &[u8]) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let missing = {
+        let mut stat = tx.prepare(
+            "select id, uploaded from images
+where not exists (select 1 from uploads where image=images.id)",
+        )?;
+        let rows = stat.query_map([], |row| {
+            Ok((row.get::<usize, String>(0)?, row.get::<usize, i64>(1)?))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    for (image_id, uploaded) in &missing {
+        let token = derived_delete_token(global_secret, image_id);
+        tx.execute(
+            "insert into uploads (token_sha256, image, uploaded) values (?, ?, ?)",
+            params![sha256_hex(token.as_bytes()), image_id, uploaded],
+        )?;
+    }
+    tx.commit()?;
+    Ok(missing.len())
+}
+
+/// what `upload` used to hand out, before every upload got its own token
+fn derived_delete_token(global_secret: &[u8], image_id: &str) -> String {
+    let mac = crate::gallery::mac(global_secret, format!("delete:{image_id}").as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&mac[..18])
+}
+
+pub fn delete_token_valid(conn: &Connection, image_id: &str, token: &str) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "select 1 from uploads where token_sha256=? and image=?",
+            [sha256_hex(token.as_bytes()).as_str(), image_id],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Forget the upload which was given this token, unless it's the image's last one: then
+/// the image should be removed, which takes the token with it, so a failed removal can be
+/// tried again. `None` if the token isn't for this image, otherwise how many uploads are left.
+pub fn release(conn: &Connection, image_id: &str, token: &str) -> Result<Option<usize>> {
+    if !delete_token_valid(conn, image_id, token)? {
+        return Ok(None);
+    }
+    let uploads = conn.query_row(
+        "select count(*) from uploads where image=?",
+        [image_id],
+        |row| row.get::<usize, i64>(0),
+    )? as usize;
+    if uploads > 1 {
+        conn.execute(
+            "delete from uploads where token_sha256=?",
+            [sha256_hex(token.as_bytes())],
+        )?;
+    }
+    Ok(Some(uploads - 1))
+}
+
+/// note files we've written for an image, e.g. its thumbnails
+pub fn record_derivatives(conn: &Connection, image_id: &str, names: &[String]) -> Result<()> {
+    let mut stat =
+        conn.prepare("insert into derivatives (image, name) values (?, ?) on conflict do nothing")?;
+    for name in names {
+        stat.execute([image_id, name])?;
+    }
+    Ok(())
+}
+
+fn derivatives(conn: &Connection, image_id: &str) -> Result<Vec<String>> {
+    let mut stat = conn.prepare("select name from derivatives where image=?")?;
+    let names = stat.query_map([image_id], |row| row.get::<usize, String>(0))?;
+    Ok(names.collect::<Result<_, _>>()?)
+}
+
+/// Remove an image, its derivatives, and every mention of it. The database is only locked
+/// to forget it, not while the files are removed. Returns false if there was nothing to delete.
+pub fn remove(
+    storage: &StorageConfig,
+    thumbs: &ThumbConfig,
+    conn: &Mutex<Connection>,
+    image_id: &str,
+) -> Result<bool> {
+    let mut found = false;
+    // what we've recorded writing, and what the presets say, for images from before that
+    let mut names = derivatives(&*conn.lock().map_err(|_| anyhow!("poison"))?, image_id)?;
+    names.extend(thumbs.thumb_names(image_id));
+    names.extend(thumbs.animated_name(image_id));
+    names.sort();
+    names.dedup();
+    for path in std::iter::once(image_id)
+        .chain(names.iter().map(String::as_str))
+        .map(|name| storage.image_path(name))
+    {
+        match fs::remove_file(&path) {
+            Ok(()) => found = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| anyhow!("removing {path:?}")),
+        }
+    }
+
+    let resized = storage.resize_dir(image_id);
+    match fs::remove_dir_all(&resized) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e).with_context(|| anyhow!("removing {resized:?}")),
+    }
+
+    let mut conn = conn.lock().map_err(|_| anyhow!("poison"))?;
+    let tx = conn.transaction()?;
+    crate::gallery::gallery_forget_image(&tx, image_id)?;
+    tx.execute("delete from uploads where image=?", [image_id])?;
+    tx.execute("delete from derivatives where image=?", [image_id])?;
+    crate::jobs::forget(&tx, image_id)?;
+    found |= 0 != tx.execute("delete from images where id=?", [image_id])?;
+    tx.commit()?;
+
+    Ok(found)
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    let mut hex = String::with_capacity(64);
+    for b in sha2::Sha256::digest(data) {
+        write!(hex, "{b:02x}").expect("writing to a string");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::ImageRecord;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_images(&conn)?;
+        let record = ImageRecord {
+            id: "e/abcdefghij.png".to_string(),
+            original_format: "image/bmp".to_string(),
+            format: "image/png".to_string(),
+            width: 640,
+            height: 480,
+            size: 1234,
+            sha256: super::sha256_hex(b""),
+            input_sha256: None,
+            uploaded: 1_500_000_000_000,
+            expires: None,
+            blurhash: Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string()),
+            caller_addr: "127.0.0.1:1234".to_string(),
+            caller_forwarded: None,
+        };
+        super::record(&conn, &record)?;
+        assert_eq!(Some(record.clone()), super::get(&conn, "e/abcdefghij.png")?);
+        assert_eq!(None, super::get(&conn, "e/klmnopqrst.png")?);
+        assert_eq!(record.blurhash, super::blurhash(&conn, "e/abcdefghij.png")?);
+        assert_eq!(None, super::blurhash(&conn, "e/klmnopqrst.png")?);
+        assert_eq!(
+            vec!["e/abcdefghij.png"],
+            super::with_sha256(&conn, &record.sha256)?
+        );
+        assert!(super::with_input_sha256(&conn, &record.sha256)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn expiry() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_images(&conn)?;
+        let record = |id: &str, expires| ImageRecord {
+            id: id.to_string(),
+            original_format: "image/png".to_string(),
+            format: "image/png".to_string(),
+            width: 1,
+            height: 1,
+            size: 1,
+            sha256: super::sha256_hex(id.as_bytes()),
+            input_sha256: None,
+            uploaded: 1_000,
+            expires,
+            blurhash: None,
+            caller_addr: "127.0.0.1:1234".to_string(),
+            caller_forwarded: None,
+        };
+        super::record(&conn, &record("e/aaaaaaaaaa.png", Some(2_000)))?;
+        super::record(&conn, &record("e/bbbbbbbbbb.png", Some(3_000)))?;
+        super::record(&conn, &record("e/cccccccccc.png", None))?;
+
+        assert!(super::expired(&conn, 1_999)?.is_empty());
+        assert_eq!(vec!["e/aaaaaaaaaa.png"], super::expired(&conn, 2_000)?);
+
+        super::extend_expiry(&conn, "e/aaaaaaaaaa.png", Some(1_500))?;
+        assert_eq!(vec!["e/aaaaaaaaaa.png"], super::expired(&conn, 2_000)?);
+        super::extend_expiry(&conn, "e/aaaaaaaaaa.png", Some(2_500))?;
+        assert!(super::expired(&conn, 2_000)?.is_empty());
+        super::extend_expiry(&conn, "e/bbbbbbbbbb.png", None)?;
+        super::extend_expiry(&conn, "e/cccccccccc.png", Some(1_000))?;
+        assert_eq!(vec!["e/aaaaaaaaaa.png"], super::expired(&conn, 10_000)?);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_twice() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_images(&conn)?;
+        super::migrate_images(&conn)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tokens() -> Result<()> {
+        use super::{delete_token_valid, issue_delete_token, release};
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_images(&conn)?;
+        let first = issue_delete_token(&conn, "e/abcdefghij.png", 1)?;
+        assert_eq!(24, first.len());
+        assert!(delete_token_valid(&conn, "e/abcdefghij.png", &first)?);
+        assert!(!delete_token_valid(&conn, "e/abcdefghik.png", &first)?);
+        assert!(!delete_token_valid(&conn, "e/abcdefghij.png", "")?);
+
+        // someone else uploads the same thing, and it's deduplicated
+        let second = issue_delete_token(&conn, "e/abcdefghij.png", 2)?;
+        assert_ne!(first, second);
+        assert_eq!(None, release(&conn, "e/abcdefghik.png", &second)?);
+        assert_eq!(Some(1), release(&conn, "e/abcdefghij.png", &second)?);
+        assert_eq!(None, release(&conn, "e/abcdefghij.png", &second)?);
+        assert!(delete_token_valid(&conn, "e/abcdefghij.png", &first)?);
+        // the last one is kept until the image has actually gone
+        assert_eq!(Some(0), release(&conn, "e/abcdefghij.png", &first)?);
+        assert_eq!(Some(0), release(&conn, "e/abcdefghij.png", &first)?);
+        Ok(())
+    }
+
+    #[test]
+    fn backfill_tokens() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_images(&conn)?;
+        let record = |id: &str| ImageRecord {
+            id: id.to_string(),
+            original_format: "image/png".to_string(),
+            format: "image/png".to_string(),
+            width: 1,
+            height: 1,
+            size: 1,
+            sha256: super::sha256_hex(id.as_bytes()),
+            input_sha256: None,
+            uploaded: 1_000,
+            expires: None,
+            blurhash: None,
+            caller_addr: "127.0.0.1:1234".to_string(),
+            caller_forwarded: None,
+        };
+        super::record(&conn, &record("e/aaaaaaaaaa.png"))?;
+        super::record(&conn, &record("e/bbbbbbbbbb.png"))?;
+        let issued = super::issue_delete_token(&conn, "e/bbbbbbbbbb.png", 2_000)?;
+
+        assert_eq!(1, super::backfill_delete_tokens(&conn, &[1, 2])?);
+        assert_eq!(0, super::backfill_delete_tokens(&conn, &[1, 2])?);
+        let old = super::derived_delete_token(&[1, 2], "e/aaaaaaaaaa.png");
+        assert!(super::delete_token_valid(&conn, "e/aaaaaaaaaa.png", &old)?);
+        // it already had its own, so the derived one was never handed out
+        let derived = super::derived_delete_token(&[1, 2], "e/bbbbbbbbbb.png");
+        assert!(!super::delete_token_valid(
+            &conn,
+            "e/bbbbbbbbbb.png",
+            &derived
+        )?);
+        assert!(super::delete_token_valid(
+            &conn,
+            "e/bbbbbbbbbb.png",
+            &issued
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            super::sha256_hex(b"")
+        );
+    }
+}
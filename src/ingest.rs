@@ -1,20 +1,33 @@
+use std::env;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Seek;
-use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
+use anyhow::Error;
 use anyhow::Result;
 use image::ImageFormat;
 use image::ImageFormat::Jpeg;
-use image::{imageops, DynamicImage};
+use image::{DynamicImage, ImageDecoder};
 use rand::distr::Alphanumeric;
 use rand::distr::Distribution;
+use rusqlite::Connection;
 use tempfile_fast::PersistableTempFile;
 
-pub fn make_readable(path: &str) -> io::Result<()> {
+use crate::images;
+use crate::images::{ImageRecord, Uploader};
+use crate::storage::StorageConfig;
+
+pub fn make_readable(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
     let mut perms = fs::File::open(path)?.metadata()?.permissions();
 
     use std::os::unix::fs::PermissionsExt;
@@ -24,6 +37,354 @@ pub fn make_readable(path: &str) -> io::Result<()> {
 
 pub type SavedImage = String;
 
+/// whether identical uploads get the same id, instead of a new copy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedupe {
+    /// every upload is stored separately, and we don't remember what was uploaded
+    Off,
+    /// uploads which re-encode to identical bytes are shared
+    Output,
+    /// as `Output`, but also match byte-identical uploads before decoding them
+    Input,
+}
+
+/// what we re-encode images as; gifs are always gifs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// png for things which look lossless, jpeg for everything else
+    Classic,
+    /// lossless webp for things which look lossless, lossy webp for everything else
+    WebP,
+    /// png for things which look lossless, avif for everything else
+    Avif,
+}
+
+/// what to do with an image's colour profile; other metadata is always dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IccProfiles {
+    /// apply it, so the stored image is plain sRGB
+    Convert,
+    /// keep it in the stored image, unless that's avif, which is converted
+    Embed,
+}
+
+pub const WEBP_QUALITY: f32 = 80.;
+
+/// rav1e is slow, so we ask it to hurry up (1-10, 10 is fastest)
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+
+/// How much we're prepared to decode. These are checked against the headers
+/// before decoding, where possible, so a tiny file can't claim to be enormous.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    /// for animations
+    pub max_frames: usize,
+    /// roughly, how much memory a decoder may allocate
+    pub max_alloc: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_width: 16_384,
+            max_height: 16_384,
+            max_pixels: 128 * 1024 * 1024,
+            max_frames: 1_000,
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), Rejected> {
+        if width > self.max_width
+            || height > self.max_height
+            || u64::from(width) * u64::from(height) > self.max_pixels
+        {
+            return Err(Rejected::TooLarge(format!(
+                "image too large: {width}x{height}, limit: {}x{}, {} pixels",
+                self.max_width, self.max_height, self.max_pixels
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IngestConfig {
+    pub dedupe: Dedupe,
+    pub output: OutputFormat,
+    pub icc_profiles: IccProfiles,
+    /// lossless output bigger than this is re-encoded lossily
+    pub max_lossless_size: usize,
+    /// try storing only what changes between frames, if it's smaller
+    pub optimise_gifs: bool,
+    /// store animated gifs as animated webps, if they're smaller
+    pub animated_webp: bool,
+    /// 1-100; every re-encode loses a little more, so this is higher than `image`'s default
+    pub jpeg_quality: u8,
+    /// how many resized variants of an image are cached; any more are made every time
+    pub max_resizes: usize,
+    pub limits: Limits,
+}
+
+impl Default for IngestConfig {
+    fn default() -> IngestConfig {
+        IngestConfig {
+            dedupe: Dedupe::Output,
+            output: OutputFormat::Classic,
+            icc_profiles: IccProfiles::Convert,
+            max_lossless_size: 1024 * 1024,
+            optimise_gifs: true,
+            animated_webp: false,
+            jpeg_quality: 90,
+            max_resizes: 16,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl IngestConfig {
+    /// `DEDUPE`: `off`, `output` (the default), or `input`
+    /// `OUTPUT_FORMAT`: `classic` (png/jpeg, the default), `webp`, or `avif`
+    /// `ICC_PROFILES`: `convert` (the default) or `embed`
+    /// `MAX_LOSSLESS_SIZE`: in bytes, default 1MiB
+    /// `OPTIMISE_GIFS`: `true` (the default) or `false`
+    /// `ANIMATED_WEBP`: `true` or `false` (the default)
+    /// `JPEG_QUALITY`: 1-100, default 90
+    /// `MAX_RESIZES`: cached variants per image, default 16
+    /// `MAX_WIDTH`, `MAX_HEIGHT`, `MAX_PIXELS`, `MAX_FRAMES`, `MAX_DECODE_MEMORY` (bytes): see `Limits`
+    pub fn from_env() -> Result<IngestConfig> {
+        let mut config = IngestConfig::default();
+        if let Some(val) = env_parse("MAX_LOSSLESS_SIZE")? {
+            config.max_lossless_size = val;
+        }
+        if let Some(val) = env_parse("OPTIMISE_GIFS")? {
+            config.optimise_gifs = val;
+        }
+        if let Some(val) = env_parse("ANIMATED_WEBP")? {
+            config.animated_webp = val;
+        }
+        if let Some(val) = env_parse("JPEG_QUALITY")? {
+            if !(1..=100).contains(&val) {
+                bail!("invalid JPEG_QUALITY: {val}, try 1-100");
+            }
+            config.jpeg_quality = val;
+        }
+        if let Some(val) = env_parse("MAX_RESIZES")? {
+            config.max_resizes = val;
+        }
+        let limits = &mut config.limits;
+        if let Some(val) = env_parse("MAX_WIDTH")? {
+            limits.max_width = val;
+        }
+        if let Some(val) = env_parse("MAX_HEIGHT")? {
+            limits.max_height = val;
+        }
+        if let Some(val) = env_parse("MAX_PIXELS")? {
+            limits.max_pixels = val;
+        }
+        if let Some(val) = env_parse("MAX_FRAMES")? {
+            limits.max_frames = val;
+        }
+        if let Some(val) = env_parse("MAX_DECODE_MEMORY")? {
+            limits.max_alloc = val;
+        }
+        if let Ok(val) = env::var("OUTPUT_FORMAT") {
+            config.output = match val.as_str() {
+                "classic" => OutputFormat::Classic,
+                "webp" => OutputFormat::WebP,
+                "avif" if cfg!(feature = "avif") => OutputFormat::Avif,
+                // we'd be unable to thumbnail what we'd written
+                "avif" => bail!("OUTPUT_FORMAT=avif needs building with --features avif"),
+                _ => bail!("invalid OUTPUT_FORMAT: {val:?}, try 'classic', 'webp' or 'avif'"),
+            };
+        }
+        if let Ok(val) = env::var("ICC_PROFILES") {
+            config.icc_profiles = match val.as_str() {
+                "convert" => IccProfiles::Convert,
+                "embed" => IccProfiles::Embed,
+                _ => bail!("invalid ICC_PROFILES: {val:?}, try 'convert' or 'embed'"),
+            };
+        }
+        if let Ok(val) = env::var("DEDUPE") {
+            config.dedupe = match val.as_str() {
+                "off" => Dedupe::Off,
+                "output" => Dedupe::Output,
+                "input" => Dedupe::Input,
+                _ => bail!("invalid DEDUPE: {val:?}, try 'off', 'output' or 'input'"),
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// `None` if it's not set, an error if it's set to nonsense
+fn env_parse<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("invalid {name}: {val:?}: {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Problems with the upload itself, rather than with us.
+#[derive(Debug)]
+pub enum Rejected {
+    /// we can't, or won't, decode this
+    Unsupported(&'static str),
+    /// it's beyond our `Limits`
+    TooLarge(String),
+    /// the decoder didn't like it
+    Malformed(&'static str),
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Unsupported(message) => write!(f, "{message}"),
+            Rejected::TooLarge(message) => write!(f, "{message}"),
+            Rejected::Malformed(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// what an upload turned out to be
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputFormat {
+    Image(ImageFormat),
+    /// `image` knows nothing of heif, so it's handled separately
+    Heif,
+}
+
+impl InputFormat {
+    fn to_mime_type(self) -> &'static str {
+        match self {
+            InputFormat::Image(format) => format.to_mime_type(),
+            InputFormat::Heif => "image/heif",
+        }
+    }
+}
+
+fn sniff(data: &[u8]) -> Result<InputFormat> {
+    // avifs typically also claim to be heifs (`mif1`), so this must go first
+    if is_avif(data) {
+        return Ok(InputFormat::Image(ImageFormat::Avif));
+    }
+
+    if is_heif(data) {
+        return Ok(InputFormat::Heif);
+    }
+
+    Ok(InputFormat::Image(guess_format(data).context(
+        Rejected::Unsupported("unrecognised image format"),
+    )?))
+}
+
+/// The brands listed in an ISO BMFF `ftyp` box, which must be the first box in the file.
+fn ftyp_brands(data: &[u8]) -> Vec<&[u8]> {
+    if data.len() < 16 || &data[4..8] != b"ftyp" {
+        return Vec::new();
+    }
+    let len = u32::from_be_bytes(data[..4].try_into().expect("fixed size")) as usize;
+    let ftyp = &data[..len.clamp(16, data.len())];
+
+    // major brand, then (skipping the minor version) the compatible brands
+    let mut brands = vec![&ftyp[8..12]];
+    brands.extend(ftyp[16..].chunks_exact(4));
+    brands
+}
+
+/// `image` only recognises a couple of specific `ftyp` box layouts
+fn is_avif(data: &[u8]) -> bool {
+    ftyp_brands(data)
+        .into_iter()
+        .any(|brand| matches!(brand, b"avif" | b"avis"))
+}
+
+/// i.e. heic, heif, and friends, as produced by phones
+fn is_heif(data: &[u8]) -> bool {
+    ftyp_brands(data).into_iter().any(|brand| {
+        matches!(
+            brand,
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1"
+        )
+    })
+}
+
+#[cfg(not(feature = "heif"))]
+fn load_heif(_data: &[u8], _limits: &Limits) -> Result<Loaded> {
+    Err(Rejected::Unsupported("heif images are not supported by this server").into())
+}
+
+/// libheif can apply the container's rotation itself, but we trust exif more, like for jpegs
+#[cfg(feature = "heif")]
+fn load_heif(data: &[u8], limits: &Limits) -> Result<Loaded> {
+    use libheif_rs::{ColorSpace, DecodingOptions, HeifContext, LibHeif, RgbChroma};
+
+    let malformed = || Rejected::Unsupported("malformed heif image");
+
+    let ctx = HeifContext::read_from_bytes(data).context(malformed())?;
+    let handle = ctx.primary_image_handle().context(malformed())?;
+    limits.check_dimensions(handle.width(), handle.height())?;
+
+    let rotation = exif_rotation(data).ok();
+    let mut options = DecodingOptions::new().ok_or_else(|| anyhow!("heif decoding options"))?;
+    options.set_ignore_transformations(rotation.is_some());
+
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), Some(options))
+        .context(malformed())?;
+
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| anyhow!("rgba heif decode has no interleaved plane"))?;
+
+    let (width, height) = (plane.width, plane.height);
+    let row_len = width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_len * height as usize);
+    for row in plane.data.chunks(plane.stride).take(height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    let mut loaded = DynamicImage::ImageRgba8(
+        image::RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| anyhow!("heif plane size mismatch"))?,
+    );
+
+    if let Some(rotation) = rotation {
+        apply_rotation(rotation, &mut loaded);
+    }
+
+    Ok(Loaded {
+        image: loaded,
+        icc: handle.color_profile_raw().map(|profile| profile.data),
+    })
+}
+
 /// the crate supports webp, but doesn't seem to detect it:
 /// https://github.com/PistonDevelopers/image/issues/660
 fn guess_format(data: &[u8]) -> Result<ImageFormat> {
@@ -40,12 +401,46 @@ fn guess_format(data: &[u8]) -> Result<ImageFormat> {
     })
 }
 
-fn load_image(data: &[u8], format: ImageFormat) -> Result<image::DynamicImage> {
-    let mut loaded =
-        image::load_from_memory_with_format(data, format).with_context(|| anyhow!("load"))?;
+/// the decoders' errors are almost always the upload's fault
+pub fn rejected_image(e: image::ImageError) -> Error {
+    use image::ImageError;
+    let rejected = match &e {
+        ImageError::Limits(_) => Rejected::TooLarge("image too large to decode".to_string()),
+        ImageError::Unsupported(_) => Rejected::Unsupported("unsupported image variant"),
+        ImageError::Decoding(_) | ImageError::IoError(_) => {
+            Rejected::Malformed("couldn't decode image")
+        }
+        ImageError::Encoding(_) | ImageError::Parameter(_) => return e.into(),
+    };
+    Error::new(e).context(rejected)
+}
+
+/// a decoded still, and the colour profile it came with, if any
+struct Loaded {
+    image: DynamicImage,
+    icc: Option<Vec<u8>>,
+}
+
+fn load_image(data: &[u8], format: ImageFormat, limits: &Limits) -> Result<Loaded> {
+    let mut reader = image::ImageReader::with_format(io::Cursor::new(data), format);
+    reader.limits(limits.image_limits());
+    let mut decoder = reader.into_decoder().map_err(rejected_image)?;
+
+    let (width, height) = decoder.dimensions();
+    limits.check_dimensions(width, height)?;
+
+    // a broken profile isn't worth rejecting the image over
+    let icc = decoder.icc_profile().unwrap_or_else(|e| {
+        eprintln!("couldn't read colour profile: {e:?}");
+        None
+    });
+
+    let mut loaded = DynamicImage::from_decoder(decoder)
+        .map_err(rejected_image)
+        .with_context(|| anyhow!("load"))?;
 
     use image::ImageFormat::*;
-    let expect_exif = matches!(format, Jpeg | WebP | Tiff);
+    let expect_exif = matches!(format, Jpeg | WebP | Tiff | Avif);
 
     if expect_exif {
         match exif_rotation(data) {
@@ -54,121 +449,438 @@ fn load_image(data: &[u8], format: ImageFormat) -> Result<image::DynamicImage> {
         }
     }
 
-    Ok(loaded)
+    Ok(Loaded { image: loaded, icc })
+}
+
+fn temp_file(storage: &StorageConfig) -> Result<PersistableTempFile> {
+    PersistableTempFile::new_in(&storage.image_dir).with_context(|| anyhow!("temp file"))
 }
 
-fn temp_file() -> Result<PersistableTempFile> {
-    PersistableTempFile::new_in("e").with_context(|| anyhow!("temp file"))
+/// the re-encoded image, before it's been given a name
+struct Encoded {
+    data: Vec<u8>,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    blurhash: Option<String>,
 }
 
-fn handle_gif(data: &[u8]) -> Result<SavedImage> {
-    let mut reader =
-        gif::Decoder::new(io::Cursor::new(data)).with_context(|| anyhow!("loading gif"))?;
+/// a placeholder is nice to have, but not worth failing an upload over
+fn blurhash_or_log(image: &DynamicImage) -> Option<String> {
+    match crate::thumbs::blurhash(image) {
+        Ok(blurhash) => Some(blurhash),
+        Err(e) => {
+            eprintln!("couldn't make a placeholder: {e:?}");
+            None
+        }
+    }
+}
 
-    let mut temp = temp_file()?;
+fn handle_gif(data: &[u8], config: &IngestConfig) -> Result<Encoded> {
+    let (width, height) = {
+        let reader = gif::Decoder::new(io::Cursor::new(data))
+            .context(Rejected::Malformed("couldn't decode gif"))?;
+        (u32::from(reader.width()), u32::from(reader.height()))
+    };
 
-    {
-        let mut encoder = gif::Encoder::new(
-            &mut temp,
-            reader.width(),
-            reader.height(),
-            reader.global_palette().unwrap_or(&[]),
-        )
-        .with_context(|| anyhow!("preparing gif"))?;
+    let gif = crate::animation::reencode_gif(data, &config.limits, config.optimise_gifs)?;
 
-        // TODO: clearly a lie, but... who even will notice?
-        encoder.set_repeat(gif::Repeat::Infinite)?;
+    // every frame is decoded anyway, if it might be wanted as a webp
+    let animation = if config.animated_webp {
+        crate::animation::gif_animation(data, &config.limits)?
+    } else {
+        None
+    };
 
-        while let Some(frame) = reader
-            .read_next_frame()
-            .with_context(|| anyhow!("reading frame"))?
-        {
-            encoder
-                .write_frame(frame)
-                .with_context(|| anyhow!("writing frame"))?;
+    // the placeholder is of just the first frame
+    let mut first = animation
+        .as_ref()
+        .and_then(|animation| animation.frames.first())
+        .map(|(frame, _)| frame.clone());
+    if first.is_none() {
+        let decoded =
+            crate::animation::each_frame(data, ImageFormat::Gif, &config.limits, |frame, _| {
+                first = Some(frame);
+                ControlFlow::Break(())
+            });
+        if let Err(e) = decoded {
+            eprintln!("couldn't make a placeholder: {e:?}");
         }
     }
+    let blurhash = first.and_then(|frame| blurhash_or_log(&DynamicImage::ImageRgba8(frame)));
+
+    if let Some(animation) = animation {
+        let webp = crate::animation::encode_webp(&animation, None)?;
+        if webp.len() < gif.len() {
+            return Ok(Encoded {
+                data: webp,
+                format: ImageFormat::WebP,
+                width,
+                height,
+                blurhash,
+            });
+        }
+    }
+
+    Ok(Encoded {
+        data: gif,
+        format: ImageFormat::Gif,
+        width,
+        height,
+        blurhash,
+    })
+}
 
-    write_out(temp, "gif")
+/// Animated pngs and webps are stored as animated webps, or as gifs,
+/// if that's smaller and loses nothing.
+fn handle_animation(data: &[u8], format: ImageFormat, config: &IngestConfig) -> Result<Encoded> {
+    let animation = crate::animation::decode_animation(data, format, &config.limits)?;
+    let webp = crate::animation::encode_webp(&animation, None)?;
+    let (data, format) = match crate::animation::encode_gif(&animation, &config.limits)? {
+        Some(gif) if gif.len() < webp.len() => (gif, ImageFormat::Gif),
+        _ => (webp, ImageFormat::WebP),
+    };
+    let blurhash = animation
+        .frames
+        .first()
+        .and_then(|(frame, _)| blurhash_or_log(&DynamicImage::ImageRgba8(frame.clone())));
+
+    Ok(Encoded {
+        data,
+        format,
+        width: animation.width,
+        height: animation.height,
+        blurhash,
+    })
 }
 
-pub fn store(data: &[u8]) -> Result<SavedImage> {
-    let guessed_format = guess_format(data)?;
+pub fn store(
+    storage: &StorageConfig,
+    config: &IngestConfig,
+    conn: &Mutex<Connection>,
+    uploader: &Uploader,
+    expires: Option<i64>,
+    data: &[u8],
+) -> Result<SavedImage> {
+    let input_sha256 = match config.dedupe {
+        Dedupe::Input => Some(images::sha256_hex(data)),
+        _ => None,
+    };
 
-    use image::ImageFormat::*;
-    if Gif == guessed_format {
-        return handle_gif(data);
+    if let Some(input_sha256) = &input_sha256 {
+        let conn = lock(conn)?;
+        let existing = images::with_input_sha256(&conn, input_sha256)?;
+        if let Some(image_id) = reuse(storage, &conn, existing, expires)? {
+            println!("exact duplicate upload of {image_id}");
+            return Ok(image_id);
+        }
+    }
+
+    let guessed_format = sniff(data)?;
+    let encoded = encode(config, data, guessed_format)?;
+    let sha256 = images::sha256_hex(&encoded.data);
+
+    if config.dedupe != Dedupe::Off {
+        let conn = lock(conn)?;
+        let existing = images::with_sha256(&conn, &sha256)?;
+        if let Some(image_id) = reuse(storage, &conn, existing, expires)? {
+            println!("upload re-encoded to existing {image_id}");
+            return Ok(image_id);
+        }
+    }
+
+    let ext = match encoded.format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpg",
+        ImageFormat::Gif => "gif",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Avif => "avif",
+        _ => unreachable!(),
+    };
+
+    let image_id = write_out(storage, &encoded.data, ext)?;
+
+    let record = ImageRecord {
+        id: image_id.clone(),
+        original_format: guessed_format.to_mime_type().to_string(),
+        format: encoded.format.to_mime_type().to_string(),
+        width: encoded.width,
+        height: encoded.height,
+        size: encoded.data.len() as i64,
+        sha256,
+        input_sha256,
+        uploaded: crate::gallery::epoch_millis(),
+        expires,
+        blurhash: encoded.blurhash,
+        caller_addr: uploader.addr.clone(),
+        caller_forwarded: uploader.forwarded_for.clone(),
+    };
+
+    let recorded = lock(conn).and_then(|conn| images::record(&conn, &record));
+
+    if let Err(e) = recorded {
+        // don't leave an image around that we have no idea about
+        let _ = fs::remove_file(storage.image_path(&image_id));
+        return Err(e.context("recording image"));
+    }
+
+    Ok(image_id)
+}
+
+fn lock(conn: &Mutex<Connection>) -> Result<MutexGuard<'_, Connection>> {
+    conn.lock().map_err(|_| anyhow!("poison"))
+}
+
+/// pick an existing copy of an upload, making sure it lives long enough for this uploader;
+/// the records may outlive the files, e.g. if someone's been tidying up by hand
+fn reuse(
+    storage: &StorageConfig,
+    conn: &Connection,
+    candidates: Vec<String>,
+    expires: Option<i64>,
+) -> Result<Option<SavedImage>> {
+    let found = candidates
+        .into_iter()
+        .find(|id| storage.image_path(id).is_file());
+    if let Some(image_id) = &found {
+        images::extend_expiry(conn, image_id, expires)?;
+    }
+    Ok(found)
+}
+
+/// what an image is actually written as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Png,
+    Jpeg,
+    LosslessWebP,
+    LossyWebP,
+    Avif,
+}
+
+impl Target {
+    fn pick(output: OutputFormat, lossless: bool) -> Target {
+        match (output, lossless) {
+            (OutputFormat::Classic, true) => Target::Png,
+            (OutputFormat::Classic, false) => Target::Jpeg,
+            (OutputFormat::WebP, true) => Target::LosslessWebP,
+            (OutputFormat::WebP, false) => Target::LossyWebP,
+            // there's no useful lossless avif
+            (OutputFormat::Avif, true) => Target::Png,
+            (OutputFormat::Avif, false) => Target::Avif,
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            Target::Png => ImageFormat::Png,
+            Target::Jpeg => ImageFormat::Jpeg,
+            Target::LosslessWebP | Target::LossyWebP => ImageFormat::WebP,
+            Target::Avif => ImageFormat::Avif,
+        }
+    }
+
+    fn is_lossless(self) -> bool {
+        matches!(self, Target::Png | Target::LosslessWebP)
     }
+}
 
-    let loaded = load_image(data, guessed_format)?;
+fn encode(config: &IngestConfig, data: &[u8], guessed_format: InputFormat) -> Result<Encoded> {
+    use image::ImageFormat::*;
+    let (loaded, lossless) = match guessed_format {
+        InputFormat::Image(Gif) => return handle_gif(data, config),
+        InputFormat::Image(format @ (Png | WebP))
+            if crate::animation::is_animation(data, format) =>
+        {
+            return handle_animation(data, format, config)
+        }
+        InputFormat::Image(Avif) if !cfg!(feature = "avif") => {
+            return Err(
+                Rejected::Unsupported("avif images are not supported by this server").into(),
+            )
+        }
+        InputFormat::Image(format) => (
+            load_image(data, format, &config.limits)?,
+            matches!(format, Png | Pnm | Tiff | Bmp | Ico | Hdr | Tga),
+        ),
+        InputFormat::Heif => (load_heif(data, &config.limits)?, false),
+    };
 
-    let mut target_format = match guessed_format {
-        Png | Pnm | Tiff | Bmp | Ico | Hdr | Tga => Png,
-        Gif => unreachable!(),
-        _ => Jpeg,
+    let (loaded, icc) = match loaded.icc {
+        Some(icc)
+            if config.icc_profiles == IccProfiles::Embed
+                && config.output != OutputFormat::Avif
+                && crate::colour::embeddable(&loaded.image, &icc) =>
+        {
+            (loaded.image, Some(icc))
+        }
+        Some(icc) => (crate::colour::to_srgb_or_keep(loaded.image, &icc), None),
+        None => (loaded.image, None),
     };
+    let icc = icc.as_deref();
 
-    let mut temp = temp_file()?;
-    write_image(temp.as_mut(), loaded.clone(), target_format).with_context(|| anyhow!("save"))?;
+    let mut target = Target::pick(config.output, lossless);
+    let mut out = encode_as(&loaded, target, icc, config).with_context(|| anyhow!("save"))?;
 
-    if target_format == Png {
+    if target.is_lossless() {
         // Chrome seems to convert everything pasted to png, even if it's huge.
         // So, if we see a png that's too big, down-convert it to a jpg,
         // and log about how proud we are of having ruined the internet.
         // Alternatively, we could record whether it was a pasted upload?
 
-        let png_length = temp
-            .metadata()
-            .with_context(|| anyhow!("temp metadata"))?
-            .len();
-        if png_length > 1024 * 1024 {
-            temp.seek(SeekFrom::Start(0))
-                .with_context(|| anyhow!("truncating temp file 2"))?;
-
-            temp.set_len(0)
-                .with_context(|| anyhow!("truncating temp file"))?;
-
-            target_format = Jpeg;
-
-            write_image(temp.as_mut(), loaded, target_format)
+        let lossless_length = out.len();
+        if lossless_length > config.max_lossless_size {
+            // ..but jpeg would flatten transparency, which is going a bit far, even for us
+            let lossy = match Target::pick(config.output, false) {
+                Target::Jpeg if uses_alpha(&loaded) => Target::LossyWebP,
+                lossy => lossy,
+            };
+            out = encode_as(&loaded, lossy, icc, config)
                 .with_context(|| anyhow!("save attempt 2"))?;
 
-            let jpeg_length = temp
-                .metadata()
-                .with_context(|| anyhow!("temp metadata 2"))?
-                .len();
             println!(
-                "png came out too big so we jpeg'd it: {} -> {}",
-                png_length, jpeg_length
+                "{target:?} came out too big so we {lossy:?}'d it: {} -> {}",
+                lossless_length,
+                out.len()
             );
+
+            target = lossy;
         }
     }
-    let ext = match target_format {
-        Png => "png",
-        Jpeg => "jpg",
-        _ => unreachable!(),
-    };
 
-    write_out(temp, ext)
+    Ok(Encoded {
+        data: out,
+        format: target.format(),
+        width: loaded.width(),
+        height: loaded.height(),
+        // if the profile was kept, the colours are a little off, but it's only a blur
+        blurhash: blurhash_or_log(&loaded),
+    })
+}
+
+/// plenty of things have an alpha channel, but few of them use it
+fn uses_alpha(im: &DynamicImage) -> bool {
+    if !im.color().has_alpha() {
+        return false;
+    }
+    let opaque = |buf: &image::RgbaImage| buf.pixels().all(|p| p[3] == u8::MAX);
+    match im.as_rgba8() {
+        Some(buf) => !opaque(buf),
+        None => !opaque(&im.to_rgba8()),
+    }
+}
+
+/// `icc` is ignored for avif, which should have been converted instead
+fn encode_as(
+    im: &DynamicImage,
+    target: Target,
+    icc: Option<&[u8]>,
+    config: &IngestConfig,
+) -> Result<Vec<u8>> {
+    match target {
+        Target::Png | Target::Jpeg => {
+            let mut out = Vec::new();
+            write_image(
+                &mut io::Cursor::new(&mut out),
+                im,
+                target.format(),
+                icc,
+                config.jpeg_quality,
+            )?;
+            Ok(out)
+        }
+        Target::LosslessWebP => write_webp(im, None, icc),
+        Target::LossyWebP => write_webp(im, Some(WEBP_QUALITY), icc),
+        Target::Avif => write_avif(im),
+    }
 }
 
-fn write_image(
+#[cfg(feature = "avif")]
+fn write_avif(im: &DynamicImage) -> Result<Vec<u8>> {
+    use image::codecs::avif::AvifEncoder;
+
+    let mut out = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(&mut out, AVIF_SPEED, AVIF_QUALITY);
+    if im.color().has_alpha() {
+        im.to_rgba8().write_with_encoder(encoder)?;
+    } else {
+        im.to_rgb8().write_with_encoder(encoder)?;
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "avif"))]
+fn write_avif(_im: &DynamicImage) -> Result<Vec<u8>> {
+    bail!("avif support not compiled in")
+}
+
+/// `image` can only write lossless webp, so we borrow libwebp for this
+pub fn write_webp(im: &DynamicImage, quality: Option<f32>, icc: Option<&[u8]>) -> Result<Vec<u8>> {
+    let (width, height) = (im.width(), im.height());
+    let lossless = quality.is_none();
+    let quality = quality.unwrap_or(75.);
+
+    let encoded = if im.color().has_alpha() {
+        let rgba = im.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, width, height).encode_simple(lossless, quality)
+    } else {
+        let rgb = im.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, width, height).encode_simple(lossless, quality)
+    }
+    .map_err(|e| anyhow!("encoding webp: {e:?}"))?;
+
+    match icc {
+        Some(icc) => crate::colour::embed_webp_icc(&encoded, icc, width, height),
+        None => Ok(encoded.to_vec()),
+    }
+}
+
+/// `jpeg_quality` is ignored for anything else
+pub fn write_image(
     dest: &mut (impl io::Write + Seek),
-    im: DynamicImage,
+    im: &DynamicImage,
     target_format: ImageFormat,
+    icc: Option<&[u8]>,
+    jpeg_quality: u8,
 ) -> Result<()> {
-    let im = match target_format {
-        Jpeg => DynamicImage::from(im.into_rgb8()),
-        _ => im,
-    };
-
-    im.write_to(dest, target_format)
-        .with_context(|| anyhow!("save"))?;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::PngEncoder;
+    use image::ImageEncoder;
+
+    match target_format {
+        ImageFormat::Png => {
+            let mut encoder = PngEncoder::new(dest);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc.to_vec())?;
+            }
+            im.write_with_encoder(encoder)
+        }
+        Jpeg => {
+            let mut encoder = JpegEncoder::new_with_quality(dest, jpeg_quality);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc.to_vec())?;
+            }
+            match im {
+                // what jpegs decode to, so there's no need to copy them
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageLuma8(_) => {
+                    im.write_with_encoder(encoder)
+                }
+                _ => im.to_rgb8().write_with_encoder(encoder),
+            }
+        }
+        _ if icc.is_some() => bail!("can't embed a colour profile in {target_format:?}"),
+        _ => im.write_to(dest, target_format),
+    }
+    .with_context(|| anyhow!("save"))?;
 
     Ok(())
 }
 
-fn write_out(mut temp: PersistableTempFile, ext: &str) -> Result<SavedImage> {
+fn write_out(storage: &StorageConfig, data: &[u8], ext: &str) -> Result<SavedImage> {
+    let mut temp = temp_file(storage)?;
+    temp.write_all(data)
+        .with_context(|| anyhow!("writing temp file"))?;
+
     let mut rand = rand::rng();
 
     for _ in 0..32768 {
@@ -178,9 +890,10 @@ fn write_out(mut temp: PersistableTempFile, ext: &str) -> Result<SavedImage> {
             .take(10)
             .collect();
         let cand = format!("e/{}.{}", rand_bit, ext);
-        temp = match temp.persist_noclobber(&cand) {
+        let path = storage.image_path(&cand);
+        temp = match temp.persist_noclobber(&path) {
             Ok(_) => {
-                make_readable(&cand)?;
+                make_readable(&path)?;
                 return Ok(cand);
             }
             Err(e) => match e.error.raw_os_error() {
@@ -204,6 +917,8 @@ fn exif_rotation(from: &[u8]) -> Result<u32> {
 }
 
 fn apply_rotation(rotation: u32, image: &mut image::DynamicImage) {
+    use image::metadata::Orientation;
+
     if rotation == 0 || rotation > 8 {
         eprintln!("crazy rot: {}", rotation);
         return;
@@ -216,28 +931,61 @@ fn apply_rotation(rotation: u32, image: &mut image::DynamicImage) {
     }
 
     if 0 != rotation & 0b010 {
-        *image = image::DynamicImage::ImageRgba8(imageops::rotate180(image));
+        image.apply_orientation(Orientation::Rotate180);
     }
 
     if 0 != rotation & 0b001 {
-        *image = image::DynamicImage::ImageRgba8(imageops::flip_horizontal(image));
+        image.apply_orientation(Orientation::FlipHorizontal);
     }
 }
 
-fn flip_diagonal(image: &image::DynamicImage) -> image::DynamicImage {
-    use image::GenericImageView;
-
-    let (width, height) = image.dimensions();
-    let mut out = image::ImageBuffer::new(height, width);
+/// i.e. transpose; keeps the colour type, so a jpeg's pixels stay rgb
+fn flip_diagonal(image: &DynamicImage) -> DynamicImage {
+    use image::metadata::Orientation;
+
+    match image {
+        DynamicImage::ImageLuma8(buf) => DynamicImage::ImageLuma8(transpose(buf)),
+        DynamicImage::ImageLumaA8(buf) => DynamicImage::ImageLumaA8(transpose(buf)),
+        DynamicImage::ImageRgb8(buf) => DynamicImage::ImageRgb8(transpose(buf)),
+        DynamicImage::ImageRgba8(buf) => DynamicImage::ImageRgba8(transpose(buf)),
+        DynamicImage::ImageLuma16(buf) => DynamicImage::ImageLuma16(transpose(buf)),
+        DynamicImage::ImageLumaA16(buf) => DynamicImage::ImageLumaA16(transpose(buf)),
+        DynamicImage::ImageRgb16(buf) => DynamicImage::ImageRgb16(transpose(buf)),
+        DynamicImage::ImageRgba16(buf) => DynamicImage::ImageRgba16(transpose(buf)),
+        DynamicImage::ImageRgb32F(buf) => DynamicImage::ImageRgb32F(transpose(buf)),
+        DynamicImage::ImageRgba32F(buf) => DynamicImage::ImageRgba32F(transpose(buf)),
+        _ => {
+            let mut out = image.rotate90();
+            out.apply_orientation(Orientation::FlipHorizontal);
+            out
+        }
+    }
+}
 
-    for y in 0..height {
-        for x in 0..width {
-            let p = image.get_pixel(x, y);
-            out.put_pixel(y, x, p);
+/// Walking the output in order would read a whole column of a big image for
+/// every row; square tiles keep both sides in the cache.
+fn transpose<P: image::Pixel>(
+    from: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
+    const TILE: usize = 64;
+    let (width, height) = (from.width() as usize, from.height() as usize);
+    let channels = usize::from(P::CHANNEL_COUNT);
+    let src = from.as_raw();
+    let mut dst = vec![<P::Subpixel as image::Primitive>::DEFAULT_MIN_VALUE; src.len()];
+
+    for tile_y in (0..height).step_by(TILE) {
+        for tile_x in (0..width).step_by(TILE) {
+            for y in tile_y..(tile_y + TILE).min(height) {
+                for x in tile_x..(tile_x + TILE).min(width) {
+                    let s = (y * width + x) * channels;
+                    let d = (x * height + y) * channels;
+                    dst[d..d + channels].copy_from_slice(&src[s..s + channels]);
+                }
+            }
         }
     }
 
-    image::DynamicImage::ImageRgba8(out)
+    image::ImageBuffer::from_raw(from.height(), from.width(), dst).expect("same number of pixels")
 }
 
 #[cfg(test)]
@@ -262,7 +1010,9 @@ mod tests {
     fn im(from: &[u8]) -> image::DynamicImage {
         use super::guess_format;
         use super::load_image;
-        load_image(from, guess_format(from).unwrap()).unwrap()
+        load_image(from, guess_format(from).unwrap(), &super::Limits::default())
+            .unwrap()
+            .image
     }
 
     fn assert_similar(expected: &image::DynamicImage, actual: &image::DynamicImage, rot: usize) {
@@ -298,7 +1048,7 @@ mod tests {
     fn orientate() {
         let plain = im(include_bytes!("../tests/orient_1.jpg"));
 
-        const FILES: [&'static [u8]; 9] = [
+        const FILES: [&[u8]; 9] = [
             &[],
             &[],
             include_bytes!("../tests/orient_2.jpg"),
@@ -310,8 +1060,7 @@ mod tests {
             include_bytes!("../tests/orient_8.jpg"),
         ];
 
-        for rot in 2..=8 {
-            let file = FILES[rot];
+        for (rot, file) in FILES.iter().enumerate().skip(2) {
             let output = im(file);
 
             if false {
@@ -331,11 +1080,243 @@ mod tests {
         }
     }
 
+    #[test]
+    fn heif_sniffing() {
+        use super::{sniff, InputFormat};
+
+        assert_eq!(
+            InputFormat::Heif,
+            sniff(include_bytes!("../tests/orient.heic")).unwrap()
+        );
+        assert_eq!(
+            InputFormat::Heif,
+            sniff(include_bytes!(
+                "../tests/prefix-only-invalid-weird-magic.heic"
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            InputFormat::Image(ImageFormat::Png),
+            sniff(include_bytes!("../tests/orient.png")).unwrap()
+        );
+
+        // a truncated ftyp box shouldn't upset anyone
+        assert!(sniff(&include_bytes!("../tests/orient.heic")[..12]).is_err());
+        assert!(sniff(b"").is_err());
+    }
+
+    #[test]
+    fn avif_sniffing() {
+        use super::{sniff, InputFormat};
+
+        // as written by libavif, which also claims `mif1`
+        let header = b"\0\0\0\x20ftypavif\0\0\0\0avifmif1miafMA1B\0\0\0\0meta";
+        assert_eq!(
+            InputFormat::Image(ImageFormat::Avif),
+            sniff(header).unwrap()
+        );
+
+        if !cfg!(feature = "avif") {
+            let err = super::encode(
+                &super::IngestConfig::default(),
+                header,
+                sniff(header).unwrap(),
+            )
+            .err()
+            .expect("no avif support");
+            assert!(err.downcast_ref::<super::Rejected>().is_some());
+        }
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_round_trip() {
+        let config = super::IngestConfig {
+            output: super::OutputFormat::Avif,
+            ..super::IngestConfig::default()
+        };
+        let jpeg = include_bytes!("../tests/orient_1.jpg");
+        let encoded = super::encode(&config, jpeg, super::sniff(jpeg).unwrap()).unwrap();
+        assert_eq!(ImageFormat::Avif, encoded.format);
+
+        let avif = im(&encoded.data);
+        assert_similar(&im(jpeg), &avif, 0);
+    }
+
+    #[cfg(not(feature = "heif"))]
+    #[test]
+    fn heif_rejected() {
+        use super::Rejected;
+
+        let weird = include_bytes!("../tests/prefix-only-invalid-weird-magic.heic");
+        let err = super::encode(
+            &super::IngestConfig::default(),
+            weird,
+            super::sniff(weird).unwrap(),
+        )
+        .err()
+        .expect("truncated heif can't be loaded");
+        assert!(matches!(
+            err.downcast_ref::<Rejected>(),
+            Some(Rejected::Unsupported(_))
+        ));
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn heif_truncated() {
+        use super::Rejected;
+
+        // just the ftyp and the start of the meta box
+        let truncated = include_bytes!("../tests/prefix-only-invalid-weird-magic.heic");
+        let err = super::load_heif(truncated, &super::Limits::default())
+            .err()
+            .expect("truncated heif can't be loaded");
+        assert!(matches!(
+            err.downcast_ref::<Rejected>(),
+            Some(Rejected::Unsupported(_))
+        ));
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn heif() {
+        let plain = im(include_bytes!("../tests/orient.png"));
+        let heif = include_bytes!("../tests/orient.heic");
+        let loaded = super::load_heif(heif, &super::Limits::default()).unwrap();
+        assert_similar(&plain, &loaded.image, 0);
+    }
+
+    fn rejection(config: &super::IngestConfig, data: &[u8]) -> super::Rejected {
+        let err = super::encode(config, data, super::sniff(data).unwrap())
+            .err()
+            .expect("should be rejected");
+        err.downcast::<super::Rejected>()
+            .expect("rejected, not broken")
+    }
+
+    #[test]
+    fn limits() {
+        use super::{IngestConfig, Rejected};
+
+        let config = IngestConfig::default();
+        let bomb = include_bytes!("../tests/30k.png");
+        assert!(matches!(rejection(&config, bomb), Rejected::TooLarge(_)));
+
+        let png = include_bytes!("../tests/orient.png");
+        let truncated = &png[..png.len() / 2];
+        assert!(matches!(
+            rejection(&config, truncated),
+            Rejected::Malformed(_)
+        ));
+
+        let big = include_bytes!("../tests/10k.png");
+        let mut config = IngestConfig::default();
+        config.limits.max_pixels = 10_000 * 9_999;
+        assert!(matches!(rejection(&config, big), Rejected::TooLarge(_)));
+        config.limits.max_pixels = 10_000 * 10_000;
+        config.limits.max_alloc = 1024;
+        assert!(matches!(rejection(&config, big), Rejected::TooLarge(_)));
+
+        let parrot = include_bytes!("../tests/parrot.gif");
+        let mut config = IngestConfig::default();
+        config.limits.max_frames = 2;
+        assert!(matches!(rejection(&config, parrot), Rejected::TooLarge(_)));
+        config.limits.max_frames = 1_000;
+        super::encode(&config, parrot, super::sniff(parrot).unwrap()).unwrap();
+    }
+
     #[test]
     fn sixteen() {
         let png = im(include_bytes!("../tests/16-bit.png"));
-        write_image(&mut io::Cursor::new(vec![]), png, ImageFormat::Jpeg)
-            .expect("able to write a loaded image, even if it was naughty");
+        write_image(
+            &mut io::Cursor::new(vec![]),
+            &png,
+            ImageFormat::Jpeg,
+            None,
+            90,
+        )
+        .expect("able to write a loaded image, even if it was naughty");
+    }
+
+    #[test]
+    fn jpeg_quality() {
+        let jpeg = include_bytes!("../tests/orient_1.jpg");
+        let upright = im(jpeg);
+        assert_eq!(image::ColorType::Rgb8, upright.color());
+
+        let write = |quality| {
+            let mut out = Vec::new();
+            write_image(
+                &mut io::Cursor::new(&mut out),
+                &upright,
+                ImageFormat::Jpeg,
+                None,
+                quality,
+            )
+            .unwrap();
+            out
+        };
+        assert!(write(40).len() < write(95).len());
+
+        let rotated = im(include_bytes!("../tests/orient_6.jpg"));
+        assert_eq!(image::ColorType::Rgb8, rotated.color());
+    }
+
+    #[test]
+    fn flip_diagonal() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(3, 2, |x, y| {
+            image::Rgb([x as u8, y as u8, 0])
+        }));
+        let flipped = super::flip_diagonal(&image);
+        assert_eq!(image::ColorType::Rgb8, flipped.color());
+        let flipped = flipped.to_rgb8();
+        assert_eq!((2, 3), flipped.dimensions());
+        for (x, y, p) in flipped.enumerate_pixels() {
+            assert_eq!([y as u8, x as u8, 0], p.0);
+        }
+    }
+
+    /// `cargo test --release -- --ignored bench_`
+    fn bench(name: &str, image: image::DynamicImage) {
+        let runs = 5;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            std::hint::black_box(super::flip_diagonal(std::hint::black_box(&image)));
+        }
+        println!(
+            "{name}: {}x{}: {:?} per flip",
+            image.width(),
+            image.height(),
+            start.elapsed() / runs
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_flip_diagonal_rgb() {
+        bench(
+            "rgb8",
+            image::DynamicImage::ImageRgb8(image::RgbImage::new(8000, 6000)),
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_flip_diagonal_rgba() {
+        bench(
+            "rgba8",
+            image::DynamicImage::ImageRgba8(image::RgbaImage::new(8000, 6000)),
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_flip_diagonal_rgb16() {
+        bench(
+            "rgb16",
+            image::DynamicImage::ImageRgb16(image::ImageBuffer::new(8000, 6000)),
+        );
     }
 
     #[test]
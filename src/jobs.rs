@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+
+/// how many times a thumbnail is attempted before we give up on it
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// the first retry is after a minute, then two, four, ...
+const RETRY_MILLIS: i64 = 60 * 1000;
+
+pub fn migrate_jobs(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "create table if not exists thumb_jobs (
+image_id varchar primary key not null,
+queued datetime not null,
+next_attempt datetime not null,
+attempts integer not null default 0,
+last_error varchar
+)",
+        [],
+    )?;
+    conn.execute(
+        "create index if not exists thumb_jobs_next_attempt on thumb_jobs (next_attempt)",
+        [],
+    )?;
+    // gifs and webps which turned out not to be animated, so they needn't be read again
+    conn.execute(
+        "create table if not exists stills (image_id varchar primary key not null)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Ask for an image to be thumbnailed. If it's already queued (or has failed), it's left alone.
+pub fn enqueue(conn: &Connection, image_id: &str, now: i64) -> Result<()> {
+    conn.execute(
+        "insert into thumb_jobs (image_id, queued, next_attempt) values (?1, ?2, ?2)
+on conflict (image_id) do nothing",
+        params![image_id, now],
+    )?;
+    Ok(())
+}
+
+/// images which are waiting to be thumbnailed, oldest first
+pub fn due(conn: &Connection, now: i64, limit: usize) -> Result<Vec<String>> {
+    let mut stat = conn.prepare(
+        "select image_id from thumb_jobs where next_attempt <= ? and attempts < ?
+order by next_attempt, queued limit ?",
+    )?;
+    let ids = stat.query_map(params![now, MAX_ATTEMPTS, limit as i64], |row| {
+        row.get::<usize, String>(0)
+    })?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+pub fn succeeded(conn: &Connection, image_id: &str) -> Result<()> {
+    conn.execute("delete from thumb_jobs where image_id=?", [image_id])?;
+    Ok(())
+}
+
+/// Record what went wrong, and put the next attempt off for a while.
+pub fn failed(conn: &Connection, image_id: &str, error: &str, now: i64) -> Result<()> {
+    conn.execute(
+        "insert into thumb_jobs (image_id, queued, next_attempt, attempts, last_error)
+values (?1, ?2, ?2 + ?3, 1, ?4)
+on conflict (image_id) do update set
+attempts=attempts + 1,
+next_attempt=?2 + (?3 << min(attempts, 16)),
+last_error=?4",
+        params![image_id, now, RETRY_MILLIS, error],
+    )?;
+    Ok(())
+}
+
+/// note that an image has no animation to thumbnail
+pub fn still(conn: &Connection, image_id: &str) -> Result<()> {
+    conn.execute(
+        "insert into stills (image_id) values (?) on conflict do nothing",
+        [image_id],
+    )?;
+    Ok(())
+}
+
+pub fn stills(conn: &Connection) -> Result<HashSet<String>> {
+    let mut stat = conn.prepare("select image_id from stills")?;
+    let ids = stat.query_map([], |row| row.get::<usize, String>(0))?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+/// the image has gone, so there's nothing left to do for it
+pub fn forget(conn: &Connection, image_id: &str) -> Result<()> {
+    conn.execute("delete from thumb_jobs where image_id=?", [image_id])?;
+    conn.execute("delete from stills where image_id=?", [image_id])?;
+    Ok(())
+}
+
+/// images we couldn't thumbnail, with how many times we tried, and the last error
+#[cfg(test)]
+pub fn failures(conn: &Connection) -> Result<Vec<(String, u32, String)>> {
+    let mut stat = conn.prepare(
+        "select image_id, attempts, last_error from thumb_jobs
+where last_error is not null order by image_id",
+    )?;
+    let failures = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    Ok(failures.collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    #[test]
+    fn queue() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_jobs(&conn)?;
+        super::migrate_jobs(&conn)?;
+
+        super::enqueue(&conn, "e/aaaaaaaaaa.png", 1_000)?;
+        super::enqueue(&conn, "e/bbbbbbbbbb.png", 2_000)?;
+        super::enqueue(&conn, "e/aaaaaaaaaa.png", 3_000)?;
+        assert!(super::due(&conn, 999, 10)?.is_empty());
+        assert_eq!(
+            vec!["e/aaaaaaaaaa.png", "e/bbbbbbbbbb.png"],
+            super::due(&conn, 2_000, 10)?
+        );
+        assert_eq!(vec!["e/aaaaaaaaaa.png"], super::due(&conn, 2_000, 1)?);
+
+        super::succeeded(&conn, "e/aaaaaaaaaa.png")?;
+        assert_eq!(vec!["e/bbbbbbbbbb.png"], super::due(&conn, 2_000, 10)?);
+        Ok(())
+    }
+
+    #[test]
+    fn retry() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_jobs(&conn)?;
+
+        // e.g. thumbnailing during upload failed, so it was never queued
+        super::failed(&conn, "e/aaaaaaaaaa.png", "broken", 0)?;
+        assert!(super::due(&conn, 59_999, 10)?.is_empty());
+        assert_eq!(1, super::due(&conn, 60_000, 10)?.len());
+
+        super::failed(&conn, "e/aaaaaaaaaa.png", "still broken", 60_000)?;
+        assert!(super::due(&conn, 179_999, 10)?.is_empty());
+        assert_eq!(1, super::due(&conn, 180_000, 10)?.len());
+        assert_eq!(
+            vec![(
+                "e/aaaaaaaaaa.png".to_string(),
+                2,
+                "still broken".to_string()
+            )],
+            super::failures(&conn)?
+        );
+
+        // queueing it again doesn't reset anything
+        super::enqueue(&conn, "e/aaaaaaaaaa.png", 0)?;
+        assert_eq!(2, super::failures(&conn)?[0].1);
+
+        for _ in 2..super::MAX_ATTEMPTS {
+            super::failed(&conn, "e/aaaaaaaaaa.png", "broken forever", 0)?;
+        }
+        assert!(super::due(&conn, i64::MAX, 10)?.is_empty());
+        assert_eq!(super::MAX_ATTEMPTS, super::failures(&conn)?[0].1);
+        Ok(())
+    }
+
+    #[test]
+    fn stills() -> Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        super::migrate_jobs(&conn)?;
+
+        super::still(&conn, "e/aaaaaaaaaa.gif")?;
+        super::still(&conn, "e/aaaaaaaaaa.gif")?;
+        super::still(&conn, "e/bbbbbbbbbb.webp")?;
+        super::enqueue(&conn, "e/bbbbbbbbbb.webp", 0)?;
+        assert_eq!(2, super::stills(&conn)?.len());
+
+        super::forget(&conn, "e/bbbbbbbbbb.webp")?;
+        assert_eq!(
+            vec!["e/aaaaaaaaaa.gif"],
+            super::stills(&conn)?.into_iter().collect::<Vec<_>>()
+        );
+        assert!(super::due(&conn, 0, 10)?.is_empty());
+        Ok(())
+    }
+}
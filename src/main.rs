@@ -1,8 +1,16 @@
+mod animation;
+mod colour;
+mod crop;
 mod gallery;
+mod images;
 pub mod ingest;
+mod jobs;
+mod resize;
+mod storage;
 #[cfg(test)]
 mod tests;
 mod thumbs;
+mod work;
 
 use std::future::IntoFuture;
 use std::io::Read;
@@ -10,11 +18,12 @@ use std::io::Write;
 use std::net::{SocketAddr, ToSocketAddrs as _};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use std::{env, fs, path};
 
 use anyhow::{anyhow, Context, Error, Result};
 use axum::body::Bytes;
-use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, State};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
@@ -25,13 +34,29 @@ use serde_json::{json, Value};
 use tokio::task::JoinSet;
 use tower_http::services::ServeDir;
 
+use crate::ingest::IngestConfig;
+use crate::storage::StorageConfig;
+use crate::thumbs::ThumbConfig;
+use crate::work::WorkPool;
+
 type Caller<'h> = (SocketAddr, Option<&'h HeaderValue>);
 
+fn uploader(caller: &Caller) -> images::Uploader {
+    images::Uploader {
+        addr: caller.0.to_string(),
+        forwarded_for: caller
+            .1
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string()),
+    }
+}
+
 struct UploadForm {
     image: Bytes,
     return_json: bool,
     return_redirect: bool,
     return_full_url: bool,
+    expires: Option<Duration>,
 }
 
 enum UploadFormStatus {
@@ -44,6 +69,7 @@ async fn extract_image_form(mut body: Multipart) -> Result<UploadFormStatus> {
     let mut return_json: bool = false;
     let mut return_redirect: bool = false;
     let mut return_full_url: bool = false;
+    let mut expires: Option<Duration> = None;
     while let Some(field) = body.next_field().await? {
         let name = field
             .name()
@@ -76,6 +102,11 @@ async fn extract_image_form(mut body: Multipart) -> Result<UploadFormStatus> {
                     ))
                 }
             },
+            "expires" if data.is_empty() => expires = None,
+            "expires" => match std::str::from_utf8(&data).ok().and_then(parse_expiry) {
+                Some(duration) => expires = Some(duration),
+                None => return Ok(UploadFormStatus::BadRequest("invalid expires value")),
+            },
             _ => (),
         }
     }
@@ -86,14 +117,45 @@ async fn extract_image_form(mut body: Multipart) -> Result<UploadFormStatus> {
             return_json,
             return_redirect,
             return_full_url,
+            expires,
         })),
         None => Ok(UploadFormStatus::BadRequest("no image provided")),
     }
 }
 
+/// `30m`, `1h`, `7d`, ... up to a year
+fn parse_expiry(val: &str) -> Option<Duration> {
+    let split = val.len().checked_sub(1)?;
+    let (count, unit) = (val.get(..split)?, val.get(split..)?);
+    let count: u64 = count.parse().ok().filter(|&c| c > 0)?;
+    let unit = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let duration = Duration::from_secs(count.checked_mul(unit)?);
+    (duration <= Duration::from_secs(366 * 24 * 60 * 60)).then_some(duration)
+}
+
+#[test]
+fn validate_expiry() {
+    assert_eq!(Some(Duration::from_secs(3600)), parse_expiry("1h"));
+    assert_eq!(Some(Duration::from_secs(7 * 86400)), parse_expiry("7d"));
+    assert_eq!(None, parse_expiry(""));
+    assert_eq!(None, parse_expiry("d"));
+    assert_eq!(None, parse_expiry("0d"));
+    assert_eq!(None, parse_expiry("-1d"));
+    assert_eq!(None, parse_expiry("1y"));
+    assert_eq!(None, parse_expiry("9999d"));
+    assert_eq!(None, parse_expiry("1ü"));
+}
+
 async fn upload(
     ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
     body: Multipart,
 ) -> (StatusCode, HeaderMap, Response) {
     let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
@@ -105,13 +167,54 @@ async fn upload(
         Err(e) => return nh(log_error("parsing image form", &caller, &e)),
     };
 
-    match ingest::store(&form.image) {
-        Ok(image_id) => {
+    let job_state = Arc::clone(&state);
+    let uploader = uploader(&caller);
+    let expires = form
+        .expires
+        .map(|duration| gallery::epoch_millis().saturating_add(duration.as_millis() as i64));
+    let stored = state.pool.run(move || {
+        let image_id = ingest::store(
+            &job_state.storage,
+            &job_state.ingest,
+            &job_state.conn,
+            &uploader,
+            expires,
+            &form.image,
+        )?;
+        let thumbs = match thumbs::thumbnail(
+            &job_state.storage,
+            &job_state.thumbs,
+            &job_state.conn,
+            &image_id,
+        ) {
+            Ok(thumbs) => thumbs,
+            Err(e) => {
+                // the image is fine, so the upload is too; the thumbnails can be retried
+                println!("thumbnailing just written {image_id:?} failed: {e:?}");
+                let conn = job_state.conn.lock().map_err(|_| anyhow!("poison"))?;
+                jobs::failed(&conn, &image_id, &format!("{e:#}"), gallery::epoch_millis())?;
+                Vec::new()
+            }
+        };
+        // it might be a duplicate, so it's the stored one that's wanted
+        let (blurhash, delete_token) = {
+            let conn = job_state.conn.lock().map_err(|_| anyhow!("poison"))?;
+            (
+                images::blurhash(&conn, &image_id)?,
+                images::issue_delete_token(&conn, &image_id, gallery::epoch_millis())?,
+            )
+        };
+        Ok((image_id, thumbs, blurhash, delete_token))
+    });
+
+    match stored.await {
+        Ok((image_id, thumbs, blurhash, delete_token)) => {
             println!("{caller:?}: {image_id}");
 
-            if let Err(e) = thumbs::thumbnail(&image_id) {
-                return nh(log_error("thumbnailing just written", &caller, &e));
-            }
+            let animated = state
+                .thumbs
+                .animated_name(&image_id)
+                .is_some_and(|name| thumbs.contains(&name));
 
             let status = if form.return_redirect {
                 StatusCode::SEE_OTHER
@@ -120,6 +223,11 @@ async fn upload(
             };
 
             let mut map = HeaderMap::new();
+            map.insert(
+                "X-Delete-Token",
+                HeaderValue::from_str(&delete_token).expect("base64"),
+            );
+
             let url = if form.return_full_url {
                 let host = match headers
                     .get("X-Forwarded-Host")
@@ -147,7 +255,15 @@ async fn upload(
                     "Content-Type",
                     HeaderValue::from_static("application/vnd.api+json; charset=utf-8"),
                 );
-                data_response(resource_object(url, "image")).into_response()
+                let mut image = resource_object(url, "image");
+                image["meta"] = json!({ "delete_token": delete_token });
+                if animated {
+                    image["meta"]["animated"] = json!(true);
+                }
+                if let Some(blurhash) = blurhash {
+                    image["meta"]["blurhash"] = json!(blurhash);
+                }
+                data_response(image).into_response()
             } else {
                 map.insert(
                     "Content-Type",
@@ -158,7 +274,7 @@ async fn upload(
 
             (status, map, resp)
         }
-        Err(e) => nh(log_error("storing image", &caller, &e)),
+        Err(e) => nh(work_error("storing image", &caller, &e)),
     }
 }
 
@@ -200,6 +316,15 @@ fn data_response(inner: Value) -> Json<Value> {
     Json(json!({ "data": inner }))
 }
 
+/// http://jsonapi.org/format/#document-compound-documents
+fn compound_response(inner: Value, included: Vec<Value>) -> Json<Value> {
+    let mut resp = data_response(inner);
+    let included = Value::Array(included);
+    json_api_validate(&included);
+    resp.0["included"] = included;
+    resp
+}
+
 /// http://jsonapi.org/format/#document-resource-objects
 fn resource_object<I: AsRef<str>>(id: I, type_: &'static str) -> Value {
     json!({ "id": id.as_ref(), "type": type_ })
@@ -214,22 +339,230 @@ fn log_error(location: &str, caller: &Caller, error: &Error) -> (StatusCode, Jso
     (StatusCode::INTERNAL_SERVER_ERROR, error_object(location))
 }
 
+/// like `log_error`, but a full work queue, or a bad upload, is the client's problem
+fn work_error(location: &str, caller: &Caller, error: &Error) -> (StatusCode, Json<Value>) {
+    if let Some(rejected) = error.downcast_ref::<ingest::Rejected>() {
+        println!("{caller:?}: rejected: {location}: {error:?}");
+        let status = match rejected {
+            ingest::Rejected::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ingest::Rejected::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ingest::Rejected::Malformed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        return (status, error_object(&rejected.to_string()));
+    }
+
+    if error.downcast_ref::<work::Saturated>().is_some() {
+        println!("{caller:?}: rejected: {location}: {error}");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            error_object("server busy, try again later"),
+        );
+    }
+    log_error(location, caller, error)
+}
+
+#[derive(serde::Deserialize)]
+struct DeleteQuery {
+    token: String,
+}
+
+#[axum_macros::debug_handler]
+async fn image_delete(
+    ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
+    Path(image): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Response {
+    let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
+
+    if !is_image_id(&image) {
+        return bad_request("invalid image id").into_response();
+    }
+
+    // the image might have been uploaded by other people too, and it stays until they're all done;
+    // the last token is only forgotten if the image is actually removed
+    let released = state
+        .conn
+        .lock()
+        .map_err(|_| anyhow!("poison"))
+        .and_then(|conn| images::release(&conn, &image, &query.token));
+    let removed = match released {
+        Ok(None) => Ok(None),
+        Ok(Some(0)) => images::remove(&state.storage, &state.thumbs, &state.conn, &image).map(Some),
+        Ok(Some(_)) => Ok(Some(true)),
+        Err(e) => Err(e),
+    };
+
+    match removed {
+        Ok(Some(true)) => {
+            println!("{caller:?}: deleted {image}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(Some(false)) => (StatusCode::NOT_FOUND, error_object("no such image")).into_response(),
+        Ok(None) => (StatusCode::FORBIDDEN, error_object("invalid delete token")).into_response(),
+        Err(e) => log_error("deleting image", &caller, &e).into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ResizeQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    fmt: Option<String>,
+    /// for fetching
+    sig: Option<String>,
+    /// for signing: the image's delete token
+    token: Option<String>,
+}
+
+impl ResizeQuery {
+    fn resize(&self) -> Result<resize::Resize, &'static str> {
+        resize::Resize::parse(self.w, self.h, self.fit.as_deref(), self.fmt.as_deref())
+    }
+}
+
+#[axum_macros::debug_handler]
+async fn resize_get(
+    ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
+    Path(image): Path<String>,
+    Query(query): Query<ResizeQuery>,
+) -> Response {
+    let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
+
+    if !is_image_id(&image) {
+        return bad_request("invalid image id").into_response();
+    }
+
+    let resize = match query.resize() {
+        Ok(resize) => resize,
+        Err(message) => return bad_request(message).into_response(),
+    };
+
+    let sig = query.sig.as_deref().unwrap_or_default();
+    if !resize::signature_valid(&state.secret, &image, &resize, sig) {
+        return (StatusCode::FORBIDDEN, error_object("invalid signature")).into_response();
+    }
+
+    if !state.storage.image_path(&image).is_file() {
+        return (StatusCode::NOT_FOUND, error_object("no such image")).into_response();
+    }
+
+    // cached variants don't need to wait for the pool
+    let cache_state = Arc::clone(&state);
+    let cache_image = image.clone();
+    let cached = tokio::task::spawn_blocking(move || {
+        resize::cached(&cache_state.storage, &cache_image, &resize)
+    })
+    .await
+    .map_err(Error::from)
+    .and_then(|cached| cached);
+    let data = match cached {
+        Ok(Some(data)) => Ok(data),
+        Ok(None) => {
+            let job_state = Arc::clone(&state);
+            state
+                .pool
+                .run(move || {
+                    resize::resized(&job_state.storage, &job_state.ingest, &image, &resize)
+                })
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match data {
+        Ok(data) => {
+            let mut map = HeaderMap::new();
+            map.insert(
+                "Content-Type",
+                HeaderValue::from_static(resize.format.to_mime_type()),
+            );
+            // images never change, so neither do their variants
+            map.insert(
+                "Cache-Control",
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            );
+            (StatusCode::OK, map, data).into_response()
+        }
+        Err(e) => work_error("resizing image", &caller, &e).into_response(),
+    }
+}
+
+/// the owner of an image (i.e. someone with its delete token) can have resizes signed
+#[axum_macros::debug_handler]
+async fn resize_sign(
+    ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
+    Path(image): Path<String>,
+    Query(query): Query<ResizeQuery>,
+) -> (StatusCode, Json<Value>) {
+    let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
+
+    if !is_image_id(&image) {
+        return bad_request("invalid image id");
+    }
+
+    let resize = match query.resize() {
+        Ok(resize) => resize,
+        Err(message) => return bad_request(message),
+    };
+
+    let token = query.token.as_deref().unwrap_or_default();
+    let valid = state
+        .conn
+        .lock()
+        .map_err(|_| anyhow!("poison"))
+        .and_then(|conn| images::delete_token_valid(&conn, &image, token));
+    match valid {
+        Ok(true) => (),
+        Ok(false) => return (StatusCode::FORBIDDEN, error_object("invalid delete token")),
+        Err(e) => return log_error("checking delete token", &caller, &e),
+    }
+
+    let url = resize::signed_url(&state.secret, &image, &resize);
+    (
+        StatusCode::OK,
+        data_response(resource_object(url, "resize")),
+    )
+}
+
 #[derive(serde::Deserialize)]
 struct GalleryAttributes {
     gallery: String,
     images: Vec<String>,
 }
 
+/// For one `image`: at most one of `to`, `before` or `after`, and its `caption` and `alt`.
+/// For the whole gallery: `title`, `description` and `cover`. Empty strings clear things.
 #[derive(serde::Deserialize)]
-struct GalleryData {
+struct GalleryPatchAttributes {
+    gallery: String,
+    image: Option<String>,
+    to: Option<usize>,
+    before: Option<String>,
+    after: Option<String>,
+    caption: Option<String>,
+    alt: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    cover: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct GalleryData<A> {
     #[serde(rename = "type")]
     type_: String,
-    attributes: GalleryAttributes,
+    attributes: A,
 }
 
 #[derive(serde::Deserialize)]
-struct GalleryInput {
-    data: GalleryData,
+struct GalleryInput<A = GalleryAttributes> {
+    data: GalleryData<A>,
 }
 
 #[axum_macros::debug_handler]
@@ -247,9 +580,6 @@ async fn gallery_put(
     let gallery_input = body.data.attributes.gallery;
     let raw_images = body.data.attributes.images;
 
-    static GALLERY_SPEC: Lazy<Regex> =
-        Lazy::new(|| Regex::new("^([a-zA-Z][a-zA-Z0-9]{3,9})!(.{4,99})$").expect("static regex"));
-
     let mut images = Vec::with_capacity(raw_images.len());
 
     for image in &raw_images {
@@ -257,24 +587,16 @@ async fn gallery_put(
             return bad_request("invalid image id");
         }
 
-        if !path::Path::new(&image).exists() {
+        if !state.storage.image_path(image).exists() {
             return bad_request("no such image");
         }
 
         images.push(image.as_str());
     }
 
-    let (gallery, private) = match GALLERY_SPEC.captures(&gallery_input) {
-        Some(captures) => (
-            captures.get(1).expect("static regex").as_str(),
-            captures.get(2).expect("static regex").as_str(),
-        ),
-        None => {
-            return bad_request(concat!(
-                "gallery format: name!password, ",
-                "4-10 letters, pass: 4+ anything"
-            ));
-        }
+    let (gallery, private) = match parse_gallery_spec(&gallery_input) {
+        Ok(spec) => spec,
+        Err(message) => return bad_request(message),
     };
 
     match gallery::gallery_store(&state.conn, &state.secret, gallery, private, &images) {
@@ -286,15 +608,178 @@ async fn gallery_put(
     }
 }
 
+/// Takes the same body as `gallery_put`; images which aren't in the gallery are ignored,
+/// as are images which have since been deleted, so they can still be tidied away.
+#[axum_macros::debug_handler]
+async fn gallery_delete(
+    ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
+    Json(body): Json<GalleryInput>,
+) -> (StatusCode, Json<Value>) {
+    let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
+    if body.data.type_ != "gallery" {
+        return bad_request("missing/invalid type: gallery");
+    }
+
+    let raw_images = body.data.attributes.images;
+    if !raw_images.iter().all(|image| is_image_id(image)) {
+        return bad_request("invalid image id");
+    }
+    let images = raw_images.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let (gallery, private) = match parse_gallery_spec(&body.data.attributes.gallery) {
+        Ok(spec) => spec,
+        Err(message) => return bad_request(message),
+    };
+
+    match gallery::gallery_remove(&state.conn, &state.secret, gallery, private, &images) {
+        Ok((public, removed)) => {
+            let mut gallery = resource_object(public, "gallery");
+            gallery["meta"] = json!({ "removed": removed });
+            (StatusCode::OK, data_response(gallery))
+        }
+        Err(e) => log_error("removing gallery items", &caller, &e),
+    }
+}
+
+/// Change the gallery, or one image in it, e.g.
+/// `{"image": "e/abcdefghij.png", "before": "e/klmnopqrst.png", "alt": "a cat, asleep"}`,
+/// or `{"title": "cats", "cover": "e/abcdefghij.png"}`.
+#[axum_macros::debug_handler]
+async fn gallery_patch(
+    ConnectInfo(conn_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<Ctx>>,
+    Json(body): Json<GalleryInput<GalleryPatchAttributes>>,
+) -> (StatusCode, Json<Value>) {
+    let caller: Caller = (conn_info, headers.get("X-Forwarded-For"));
+    if body.data.type_ != "gallery" {
+        return bad_request("missing/invalid type: gallery");
+    }
+    let attributes = body.data.attributes;
+
+    let to = match (attributes.to, attributes.before, attributes.after) {
+        (None, None, None) => None,
+        (Some(index), None, None) => Some(gallery::Move::To(index)),
+        (None, Some(other), None) => Some(gallery::Move::Before(other)),
+        (None, None, Some(other)) => Some(gallery::Move::After(other)),
+        _ => return bad_request("only one of: to, before, after"),
+    };
+    let (caption, alt) = (attributes.caption.as_deref(), attributes.alt.as_deref());
+    let (title, description) = (
+        attributes.title.as_deref(),
+        attributes.description.as_deref(),
+    );
+    let cover = attributes.cover.as_deref();
+
+    let describing = caption.is_some() || alt.is_some();
+    let about_gallery = title.is_some() || description.is_some() || cover.is_some();
+    if to.is_none() && !describing && !about_gallery {
+        return bad_request(
+            "nothing to change: to, before, after, caption, alt, title, description or cover",
+        );
+    }
+    match (&attributes.image, to.is_some() || describing) {
+        (None, true) => return bad_request("missing image to move or describe"),
+        (Some(_), false) => {
+            return bad_request("nothing to do to image: to, before, after, caption or alt")
+        }
+        _ => (),
+    }
+    if [caption, alt, title, description]
+        .into_iter()
+        .flatten()
+        .any(|text| text.chars().count() > gallery::MAX_TEXT_LEN)
+    {
+        return bad_request(&format!(
+            "text is limited to {} characters",
+            gallery::MAX_TEXT_LEN
+        ));
+    }
+    if cover.is_some_and(|cover| !cover.is_empty() && !is_image_id(cover)) {
+        return bad_request("invalid cover image id");
+    }
+
+    let (gallery, private) = match parse_gallery_spec(&attributes.gallery) {
+        Ok(spec) => spec,
+        Err(message) => return bad_request(message),
+    };
+
+    let patch = gallery::Patch {
+        image: attributes.image.as_deref(),
+        to,
+        caption,
+        alt,
+        title,
+        description,
+        cover,
+    };
+    let patched = gallery::gallery_patch(&state.conn, &state.secret, gallery, private, &patch);
+
+    match patched {
+        Ok(Ok(public)) => (
+            StatusCode::OK,
+            data_response(resource_object(public, "gallery")),
+        ),
+        Ok(Err(missing)) => (
+            StatusCode::NOT_FOUND,
+            error_object(match missing {
+                gallery::Missing::Gallery => "no such gallery",
+                gallery::Missing::Image => "image not in gallery",
+                gallery::Missing::Target => "image to move next to not in gallery",
+                gallery::Missing::Cover => "cover image not in gallery",
+            }),
+        ),
+        Err(e) => log_error("changing gallery", &caller, &e),
+    }
+}
+
+/// `name!password`, as typed in by the user; the password can't be checked, only hashed
+fn parse_gallery_spec(spec: &str) -> Result<(&str, &str), &'static str> {
+    static GALLERY_SPEC: Lazy<Regex> =
+        Lazy::new(|| Regex::new("^([a-zA-Z][a-zA-Z0-9]{3,9})!(.{4,99})$").expect("static regex"));
+
+    match GALLERY_SPEC.captures(spec) {
+        Some(captures) => Ok((
+            captures.get(1).expect("static regex").as_str(),
+            captures.get(2).expect("static regex").as_str(),
+        )),
+        None => Err(concat!(
+            "gallery format: name!password, ",
+            "4-10 letters, pass: 4+ anything"
+        )),
+    }
+}
+
+#[test]
+fn test_gallery_spec() {
+    assert_eq!(
+        Ok(("potato", "carrots")),
+        parse_gallery_spec("potato!carrots")
+    );
+    assert_eq!(
+        Ok(("potato", "car!rots")),
+        parse_gallery_spec("potato!car!rots")
+    );
+    assert!(parse_gallery_spec("potato").is_err());
+    assert!(parse_gallery_spec("pot!carrots").is_err());
+    assert!(parse_gallery_spec("potato!car").is_err());
+    assert!(parse_gallery_spec("9potato!carrots").is_err());
+}
+
 pub fn is_image_id(image: &str) -> bool {
-    static RE: Lazy<Regex> =
-        Lazy::new(|| Regex::new("^e/[a-zA-Z0-9]{10}\\.(?:png|jpg|gif)$").expect("static regex"));
+    static RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new("^e/[a-zA-Z0-9]{10}\\.(?:png|jpg|gif|webp|avif)$").expect("static regex")
+    });
     RE.is_match(image)
 }
 
 #[test]
 fn validate_image_id() {
     assert!(is_image_id("e/abcdefghij.png"));
+    assert!(is_image_id("e/abcdefghij.webp"));
+    assert!(is_image_id("e/abcdefghij.avif"));
     assert!(!is_image_id(" e/abcdefghij.png"));
     assert!(!is_image_id("e/abcdefghi.png"));
 }
@@ -323,21 +808,132 @@ async fn gallery_get(
         }
     };
 
-    match gallery::gallery_list_all(&conn, &public) {
-        Ok(resp) => {
-            let values: Vec<_> = resp
-                .into_iter()
-                .map(|id| json!({"id": id, "type": "image"}))
-                .collect();
-            (StatusCode::OK, data_response(json!(values)))
+    let listed = gallery::gallery_list_all(&conn, &public).and_then(|listed| {
+        let info = gallery::gallery_info(&conn, &public)?;
+        let included = listed
+            .into_iter()
+            .map(|gallery::GalleryImage { id, caption, alt }| {
+                let mut meta = serde_json::Map::new();
+                // so the ui can show the animated thumbnail, without guessing
+                let animated = state.thumbs.animated_name(&id);
+                if animated.is_some_and(|name| state.storage.image_path(&name).is_file()) {
+                    meta.insert("animated".to_string(), json!(true));
+                }
+                if let Some(blurhash) = images::blurhash(&conn, &id)? {
+                    meta.insert("blurhash".to_string(), json!(blurhash));
+                }
+                let mut image = json!({
+                    "id": id,
+                    "type": "image",
+                    "attributes": { "caption": caption, "alt": alt },
+                });
+                if !meta.is_empty() {
+                    image["meta"] = Value::Object(meta);
+                }
+                Ok(image)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((info, included))
+    });
+
+    match listed {
+        Ok((info, included)) => {
+            let linkage = |image: &Value| json!({"id": image["id"], "type": "image"});
+            let cover = info.as_ref().and_then(|info| info.cover.as_ref());
+            let mut gallery = resource_object(public, "gallery");
+            gallery["attributes"] = json!({
+                "title": info.as_ref().and_then(|info| info.title.as_ref()),
+                "description": info.as_ref().and_then(|info| info.description.as_ref()),
+                "created": info.as_ref().map(|info| info.created),
+                "updated": info.as_ref().map(|info| info.updated),
+            });
+            gallery["relationships"] = json!({
+                "images": { "data": included.iter().map(linkage).collect::<Vec<_>>() },
+                "cover": { "data": cover.map(|id| json!({"id": id, "type": "image"})) },
+            });
+            (StatusCode::OK, compound_response(gallery, included))
         }
         Err(e) => log_error("listing gallery", &caller, &e),
     }
 }
 
-fn app_secret() -> Result<[u8; 32], Error> {
+/// Find anything missing a thumbnail, then thumbnail whatever's queued, forever.
+/// This is all in the background, on the work pool, so a big backlog (or a broken image)
+/// doesn't stop us serving.
+async fn thumbnail_jobs(state: Arc<Ctx>) {
+    let scan_state = Arc::clone(&state);
+    let queued = tokio::task::spawn_blocking(move || {
+        thumbs::queue_missing_thumbs(&scan_state.storage, &scan_state.thumbs, &scan_state.conn)
+    })
+    .await;
+
+    match queued {
+        Ok(Ok(queued)) => println!("{queued} image(s) need thumbnailing"),
+        Ok(Err(e)) => println!("looking for missing thumbnails failed: {e:?}"),
+        Err(e) => println!("looking for missing thumbnails panicked: {e:?}"),
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        // it's the same decoding as uploads do, so it shares their limit
+        let job_state = Arc::clone(&state);
+        let ran = state
+            .pool
+            .run(move || {
+                thumbs::run_thumb_jobs(&job_state.storage, &job_state.thumbs, &job_state.conn)
+            })
+            .await;
+
+        match ran {
+            Ok(0) => (),
+            Ok(attempted) => println!("thumbnailed {attempted} queued image(s)"),
+            // uploads come first; it'll still be queued next time
+            Err(e) if e.downcast_ref::<work::Saturated>().is_some() => (),
+            Err(e) => println!("thumbnailing queued images failed: {e:?}"),
+        }
+    }
+}
+
+/// remove expired images every so often, forever
+async fn sweep_expired(state: Arc<Ctx>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let state = Arc::clone(&state);
+        let swept =
+            tokio::task::spawn_blocking(move || sweep(&state, gallery::epoch_millis())).await;
+
+        match swept {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => println!("sweeping expired images failed: {e:?}"),
+            Err(e) => println!("sweeping expired images panicked: {e:?}"),
+        }
+    }
+}
+
+/// Remove the images which had expired by `now`, returning how many went.
+fn sweep(state: &Ctx, now: i64) -> Result<usize> {
+    let expired = {
+        let conn = state.conn.lock().map_err(|_| anyhow!("poison"))?;
+        images::expired(&conn, now)?
+    };
+    let mut removed = 0;
+    for image in expired {
+        // it'll be tried again next time, but the others needn't wait for it
+        if let Err(e) = images::remove(&state.storage, &state.thumbs, &state.conn, &image) {
+            println!("removing expired {image:?} failed: {e:?}");
+            continue;
+        }
+        println!("expired {image}");
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+fn app_secret(storage: &StorageConfig) -> Result<[u8; 32], Error> {
     let mut buf = [0u8; 32];
-    let path = path::Path::new(".secret");
+    let path = storage.secret_path.as_path();
     if path.exists() {
         fs::File::open(path)?.read_exact(&mut buf)?;
     } else {
@@ -347,24 +943,44 @@ fn app_secret() -> Result<[u8; 32], Error> {
     Ok(buf)
 }
 
-fn gallery_db() -> Result<rusqlite::Connection, Error> {
-    Ok(rusqlite::Connection::open("gallery.db")?)
+fn gallery_db(storage: &StorageConfig) -> Result<rusqlite::Connection, Error> {
+    Ok(rusqlite::Connection::open(&storage.db_path)?)
 }
 
 #[derive(Clone)]
 struct Ctx {
     conn: Arc<Mutex<rusqlite::Connection>>,
     secret: [u8; 32],
+    storage: StorageConfig,
+    ingest: IngestConfig,
+    thumbs: ThumbConfig,
+    pool: WorkPool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    fs::create_dir_all("e")
-        .with_context(|| anyhow!("creating storage directory inside {:?}", env::current_dir()))?;
-    let conn = gallery_db()?;
+    let storage = StorageConfig::from_env();
+    fs::create_dir_all(&storage.data_dir)
+        .with_context(|| anyhow!("creating data directory {:?}", storage.data_dir))?;
+    fs::create_dir_all(&storage.image_dir)
+        .with_context(|| anyhow!("creating storage directory {:?}", storage.image_dir))?;
+    let conn =
+        gallery_db(&storage).with_context(|| anyhow!("opening database {:?}", storage.db_path))?;
     gallery::migrate_gallery(&conn)?;
-    thumbs::generate_all_thumbs()?;
-    let secret = app_secret()?;
+    images::migrate_images(&conn)?;
+    jobs::migrate_jobs(&conn)?;
+    let secret = app_secret(&storage)
+        .with_context(|| anyhow!("loading secret {:?}", storage.secret_path))?;
+    let backfilled = images::backfill_delete_tokens(&conn, &secret)?;
+    if backfilled > 0 {
+        println!("kept the delete tokens of {backfilled} older image(s)");
+    }
+    let pool = WorkPool::from_env()?;
+    let ingest = IngestConfig::from_env()?;
+    let thumbs = ThumbConfig {
+        limits: ingest.limits.clone(),
+        ..ThumbConfig::from_env()?
+    };
 
     let dist = env::var("FRONTEND_DIR").unwrap_or_else(|_| "dist".to_string());
     let dist = fs::canonicalize(&dist).with_context(|| {
@@ -386,20 +1002,32 @@ async fn main() -> Result<()> {
     let ctx = Arc::new(Ctx {
         conn: Arc::new(Mutex::new(conn)),
         secret,
+        storage,
+        ingest,
+        thumbs,
+        pool,
     });
 
+    tokio::spawn(sweep_expired(Arc::clone(&ctx)));
+    tokio::spawn(thumbnail_jobs(Arc::clone(&ctx)));
+
     let serve_dir = |p: &path::Path| ServeDir::new(p).call_fallback_on_method_not_allowed(true);
 
     const MB: usize = 1024 * 1024;
 
-    use axum::routing::{get, post, put};
+    use axum::routing::{delete, get, post, put};
     let app = axum::Router::new()
         .route("/api/upload", post(upload))
+        .route("/api/image/{*image}", delete(image_delete))
+        .route("/api/resize/{*image}", get(resize_get).post(resize_sign))
         .route("/api/gallery/{public}", get(gallery_get))
-        .route("/api/gallery", put(gallery_put))
+        .route(
+            "/api/gallery",
+            put(gallery_put).patch(gallery_patch).delete(gallery_delete),
+        )
         .layer(DefaultBodyLimit::max(10 * MB))
         .with_state(Arc::clone(&ctx))
-        .nest_service("/e", serve_dir(path::Path::new("e")))
+        .nest_service("/e", serve_dir(&ctx.storage.image_dir))
         .fallback_service(serve_dir(dist.as_path()));
 
     let mut servers = JoinSet::new();
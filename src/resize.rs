@@ -0,0 +1,340 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+
+use crate::ingest::IngestConfig;
+use crate::storage::StorageConfig;
+
+/// nobody is embedding anything bigger than this
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// how the image is fitted into the requested size; it's never enlarged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fit {
+    /// shrink to fit inside the box, keeping the aspect ratio
+    Contain,
+    /// shrink to cover the box, then crop off whatever's outside it, from the middle
+    Cover,
+}
+
+impl Fit {
+    fn name(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeFormat {
+    Jpeg,
+    Png,
+    /// lossy
+    WebP,
+}
+
+impl ResizeFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ResizeFormat::Jpeg => "jpg",
+            ResizeFormat::Png => "png",
+            ResizeFormat::WebP => "webp",
+        }
+    }
+
+    pub fn to_mime_type(self) -> &'static str {
+        match self {
+            ResizeFormat::Jpeg => ImageFormat::Jpeg.to_mime_type(),
+            ResizeFormat::Png => ImageFormat::Png.to_mime_type(),
+            ResizeFormat::WebP => ImageFormat::WebP.to_mime_type(),
+        }
+    }
+}
+
+/// A variant of an image, as asked for in the query string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: ResizeFormat,
+}
+
+impl Resize {
+    /// `fit` is `contain` (the default) or `cover`; `fmt` is `jpg`, `png` or `webp` (the default)
+    pub fn parse(
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Option<&str>,
+        format: Option<&str>,
+    ) -> Result<Resize, &'static str> {
+        if width.is_none() && height.is_none() {
+            return Err("at least one of w and h is required");
+        }
+        if [width, height]
+            .into_iter()
+            .flatten()
+            .any(|v| v == 0 || v > MAX_DIMENSION)
+        {
+            return Err("w and h must be 1-4096");
+        }
+        let fit = match fit {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some(_) => return Err("fit must be 'contain' or 'cover'"),
+        };
+        let format = match format {
+            None | Some("webp") => ResizeFormat::WebP,
+            Some("jpg") | Some("jpeg") => ResizeFormat::Jpeg,
+            Some("png") => ResizeFormat::Png,
+            Some(_) => return Err("fmt must be 'jpg', 'png' or 'webp'"),
+        };
+        Ok(Resize {
+            width,
+            height,
+            fit,
+            format,
+        })
+    }
+
+    /// the canonical form, which is what's signed
+    pub fn query(&self) -> String {
+        let mut query = String::new();
+        if let Some(width) = self.width {
+            query.push_str(&format!("w={width}&"));
+        }
+        if let Some(height) = self.height {
+            query.push_str(&format!("h={height}&"));
+        }
+        query.push_str(&format!(
+            "fit={}&fmt={}",
+            self.fit.name(),
+            self.format.name()
+        ));
+        query
+    }
+
+    /// e.g. `320x0-contain.webp`
+    fn cache_name(&self) -> String {
+        format!(
+            "{}x{}-{}.{}",
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            self.fit.name(),
+            self.format.name()
+        )
+    }
+}
+
+/// Anyone can fetch a variant with this, but only we can make it,
+/// so nobody can fill the disc with every possible size.
+pub fn signature(global_secret: &[u8], image_id: &str, resize: &Resize) -> String {
+    let message = format!("resize:{image_id}?{}", resize.query());
+    let mac = crate::gallery::mac(global_secret, message.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&mac[..18])
+}
+
+pub fn signature_valid(global_secret: &[u8], image_id: &str, resize: &Resize, sig: &str) -> bool {
+    crate::gallery::tokens_equal(&signature(global_secret, image_id, resize), sig)
+}
+
+/// relative to the site root, like image ids
+pub fn signed_url(global_secret: &[u8], image_id: &str, resize: &Resize) -> String {
+    format!(
+        "api/resize/{image_id}?{}&sig={}",
+        resize.query(),
+        signature(global_secret, image_id, resize)
+    )
+}
+
+/// a previously produced variant, if there is one
+pub fn cached(storage: &StorageConfig, image_id: &str, resize: &Resize) -> Result<Option<Vec<u8>>> {
+    let path = storage.resize_dir(image_id).join(resize.cache_name());
+    match fs::read(&path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| anyhow!("reading {path:?}")),
+    }
+}
+
+fn cached_count(storage: &StorageConfig, image_id: &str) -> Result<usize> {
+    let dir = storage.resize_dir(image_id);
+    match fs::read_dir(&dir) {
+        Ok(entries) => Ok(entries.count()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| anyhow!("listing {dir:?}")),
+    }
+}
+
+/// Produce the variant, and cache it, so it's only done once.
+pub fn resized(
+    storage: &StorageConfig,
+    config: &IngestConfig,
+    image_id: &str,
+    resize: &Resize,
+) -> Result<Vec<u8>> {
+    if let Some(data) = cached(storage, image_id, resize)? {
+        return Ok(data);
+    }
+
+    let original =
+        fs::read(storage.image_path(image_id)).with_context(|| anyhow!("reading {image_id:?}"))?;
+    let image = crate::colour::load_srgb(&original, &config.limits)?;
+    let image = resize_image(&image, resize);
+
+    let data = match resize.format {
+        ResizeFormat::WebP => {
+            crate::ingest::write_webp(&image, Some(crate::ingest::WEBP_QUALITY), None)?
+        }
+        ResizeFormat::Jpeg | ResizeFormat::Png => {
+            let format = match resize.format {
+                ResizeFormat::Jpeg => ImageFormat::Jpeg,
+                _ => ImageFormat::Png,
+            };
+            let mut out = Vec::new();
+            crate::ingest::write_image(
+                &mut io::Cursor::new(&mut out),
+                &image,
+                format,
+                None,
+                config.jpeg_quality,
+            )?;
+            out
+        }
+    };
+
+    // anyone can get sizes signed, so there's only so much disc each image gets
+    if cached_count(storage, image_id)? >= config.max_resizes {
+        return Ok(data);
+    }
+
+    let dir = storage.resize_dir(image_id);
+    fs::create_dir_all(&dir).with_context(|| anyhow!("creating {dir:?}"))?;
+    let mut temp = tempfile_fast::PersistableTempFile::new_in(&dir)?;
+    temp.write_all(&data)?;
+    match temp.persist_noclobber(dir.join(resize.cache_name())) {
+        Ok(()) => (),
+        // someone else asked for it at the same time; theirs is just as good
+        Err(e) if e.error.raw_os_error() == Some(libc::EEXIST) => (),
+        Err(e) => bail!("caching resized image: {:?}", e.error),
+    }
+
+    Ok(data)
+}
+
+fn resize_image(image: &DynamicImage, resize: &Resize) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let filter = FilterType::CatmullRom;
+
+    match (resize.fit, resize.width, resize.height) {
+        (Fit::Cover, Some(box_width), Some(box_height)) => {
+            // shrink the box until it fits in the image, so the crop is right, but nothing's enlarged
+            let scale = (f64::from(width) / f64::from(box_width))
+                .min(f64::from(height) / f64::from(box_height))
+                .min(1.);
+            let box_width = ((f64::from(box_width) * scale).round() as u32).max(1);
+            let box_height = ((f64::from(box_height) * scale).round() as u32).max(1);
+            image.resize_to_fill(box_width, box_height, filter)
+        }
+        // with only one side, there's nothing to cover
+        (_, box_width, box_height) => {
+            let box_width = box_width.unwrap_or(u32::MAX);
+            let box_height = box_height.unwrap_or(u32::MAX);
+            if width <= box_width && height <= box_height {
+                image.clone()
+            } else {
+                image.resize(box_width, box_height, filter)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+
+    use super::{Fit, Resize, ResizeFormat};
+
+    #[test]
+    fn parse() {
+        let resize = Resize::parse(Some(320), None, None, None).unwrap();
+        assert_eq!(Fit::Contain, resize.fit);
+        assert_eq!(ResizeFormat::WebP, resize.format);
+        assert_eq!("w=320&fit=contain&fmt=webp", resize.query());
+        assert_eq!(
+            resize,
+            Resize::parse(Some(320), None, Some("contain"), Some("webp")).unwrap()
+        );
+
+        assert!(Resize::parse(None, None, None, None).is_err());
+        assert!(Resize::parse(Some(0), None, None, None).is_err());
+        assert!(Resize::parse(None, Some(4097), None, None).is_err());
+        assert!(Resize::parse(Some(1), None, Some("stretch"), None).is_err());
+        assert!(Resize::parse(Some(1), None, None, Some("gif")).is_err());
+    }
+
+    #[test]
+    fn signature() {
+        let resize = Resize::parse(Some(320), Some(200), Some("cover"), Some("jpg")).unwrap();
+        let sig = super::signature(&[1, 2], "e/abcdefghij.png", &resize);
+        assert!(super::signature_valid(
+            &[1, 2],
+            "e/abcdefghij.png",
+            &resize,
+            &sig
+        ));
+        assert!(!super::signature_valid(
+            &[1, 3],
+            "e/abcdefghij.png",
+            &resize,
+            &sig
+        ));
+        assert!(!super::signature_valid(
+            &[1, 2],
+            "e/abcdefghik.png",
+            &resize,
+            &sig
+        ));
+        let bigger = Resize {
+            width: Some(3200),
+            ..resize
+        };
+        assert!(!super::signature_valid(
+            &[1, 2],
+            "e/abcdefghij.png",
+            &bigger,
+            &sig
+        ));
+        assert_eq!(
+            format!("api/resize/e/abcdefghij.png?w=320&h=200&fit=cover&fmt=jpg&sig={sig}"),
+            super::signed_url(&[1, 2], "e/abcdefghij.png", &resize)
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(400, 200));
+        let size = |w, h, fit| {
+            let resize = Resize::parse(w, h, Some(fit), None).unwrap();
+            let resized = super::resize_image(&image, &resize);
+            (resized.width(), resized.height())
+        };
+        assert_eq!((100, 50), size(Some(100), None, "contain"));
+        assert_eq!((200, 100), size(None, Some(100), "contain"));
+        assert_eq!((100, 50), size(Some(100), Some(100), "contain"));
+        assert_eq!((400, 200), size(Some(1000), None, "contain"));
+        assert_eq!((100, 100), size(Some(100), Some(100), "cover"));
+        assert_eq!((100, 50), size(Some(100), None, "cover"));
+        // the right shape, but no bigger than the original
+        assert_eq!((200, 200), size(Some(1000), Some(1000), "cover"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Where everything the server writes ends up on disc.
+///
+/// Image ids (`e/abcdefghij.png`) are url-relative; they're mapped into
+/// `image_dir` by [`StorageConfig::image_path`].
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub data_dir: PathBuf,
+    pub image_dir: PathBuf,
+    pub db_path: PathBuf,
+    pub secret_path: PathBuf,
+    /// resized images, which can be thrown away at any time
+    pub cache_dir: PathBuf,
+}
+
+impl StorageConfig {
+    /// the historical layout: `e/`, `gallery.db`, `.secret` and `cache/`, all inside `data_dir`
+    pub fn in_dir(data_dir: impl Into<PathBuf>) -> StorageConfig {
+        let data_dir = data_dir.into();
+        StorageConfig {
+            image_dir: data_dir.join("e"),
+            db_path: data_dir.join("gallery.db"),
+            secret_path: data_dir.join(".secret"),
+            cache_dir: data_dir.join("cache"),
+            data_dir,
+        }
+    }
+
+    /// `DATA_DIR` (default: the working directory), with
+    /// `IMAGE_DIR`, `GALLERY_DB`, `SECRET_FILE` and `CACHE_DIR` overriding the individual paths
+    pub fn from_env() -> StorageConfig {
+        let var = |name: &str| env::var_os(name).filter(|v| !v.is_empty());
+        let mut config =
+            StorageConfig::in_dir(var("DATA_DIR").unwrap_or_else(|| OsString::from(".")));
+        if let Some(dir) = var("IMAGE_DIR") {
+            config.image_dir = dir.into();
+        }
+        if let Some(path) = var("GALLERY_DB") {
+            config.db_path = path.into();
+        }
+        if let Some(path) = var("SECRET_FILE") {
+            config.secret_path = path.into();
+        }
+        if let Some(dir) = var("CACHE_DIR") {
+            config.cache_dir = dir.into();
+        }
+        config
+    }
+
+    /// `e/abcdefghij.png` -> `{image_dir}/abcdefghij.png`
+    pub fn image_path(&self, image_id: &str) -> PathBuf {
+        self.image_dir
+            .join(image_id.strip_prefix("e/").unwrap_or(image_id))
+    }
+
+    /// `e/abcdefghij.png` -> `{cache_dir}/abcdefghij.png/`, holding all its resized variants
+    pub fn resize_dir(&self, image_id: &str) -> PathBuf {
+        self.cache_dir
+            .join(image_id.strip_prefix("e/").unwrap_or(image_id))
+    }
+
+    /// the inverse of `image_path`, for things found by listing `image_dir`
+    pub fn image_id(&self, path: &Path) -> Option<String> {
+        let name = path.strip_prefix(&self.image_dir).ok()?.to_str()?;
+        Some(format!("e/{name}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::StorageConfig;
+
+    #[test]
+    fn layout() {
+        let config = StorageConfig::in_dir("/srv/quad");
+        assert_eq!(Path::new("/srv/quad/e"), config.image_dir);
+        assert_eq!(Path::new("/srv/quad/gallery.db"), config.db_path);
+        assert_eq!(
+            Path::new("/srv/quad/cache/abcdefghij.png"),
+            config.resize_dir("e/abcdefghij.png")
+        );
+        assert_eq!(
+            Path::new("/srv/quad/e/abcdefghij.png"),
+            config.image_path("e/abcdefghij.png")
+        );
+        assert_eq!(
+            Some("e/abcdefghij.png".to_string()),
+            config.image_id(&config.image_path("e/abcdefghij.png"))
+        );
+    }
+}
@@ -1,27 +1,82 @@
-use std::env;
 use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use tempfile::TempDir;
 
-use crate::ingest::store;
+use crate::images;
+use crate::images::Uploader;
+use crate::ingest::{store, Dedupe, IccProfiles, IngestConfig, OutputFormat};
+use crate::storage::StorageConfig;
+use crate::thumbs::ThumbConfig;
 
-#[test]
-fn write_an_image() -> Result<()> {
-    // TODO: race central
-    let d = tempfile::Builder::new().prefix("quad-image").tempdir()?;
-    env::set_current_dir(d.path())?;
+/// Somewhere to store images, and a database to record them in; it's all gone when dropped.
+struct Fixture {
+    _dir: TempDir,
+    storage: StorageConfig,
+    conn: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl Fixture {
+    fn new() -> Result<Fixture> {
+        let dir = tempfile::Builder::new().prefix("quad-image").tempdir()?;
+        let storage = StorageConfig::in_dir(dir.path());
+        fs::create_dir(&storage.image_dir)?;
+
+        let conn = rusqlite::Connection::open_in_memory()?;
+        images::migrate_images(&conn)?;
+        crate::jobs::migrate_jobs(&conn)?;
+        crate::gallery::migrate_gallery(&conn)?;
+
+        Ok(Fixture {
+            _dir: dir,
+            storage,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// as it would be uploaded, to keep forever
+    fn store(&self, config: &IngestConfig, data: &[u8]) -> Result<String> {
+        store(&self.storage, config, &self.conn, &uploader(), None, data)
+    }
 
-    let mut e = d.path().to_path_buf();
-    e.push("e");
-    fs::create_dir(&e)?;
+    /// what the handlers are given, sharing this storage and database
+    fn ctx(&self) -> Arc<crate::Ctx> {
+        Arc::new(crate::Ctx {
+            conn: Arc::clone(&self.conn),
+            secret: [1; 32],
+            storage: self.storage.clone(),
+            ingest: IngestConfig::default(),
+            thumbs: ThumbConfig::default(),
+            pool: crate::work::WorkPool::new(1, 1),
+        })
+    }
+}
 
-    let mut input = d.path().to_path_buf();
-    input.push("test.png");
+fn caller() -> ConnectInfo<SocketAddr> {
+    ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234)))
+}
 
-    store(include_bytes!("test.png"))?;
-    store(include_bytes!("../tests/parrot.gif"))?;
+fn uploader() -> Uploader {
+    Uploader {
+        addr: "127.0.0.1:1234".to_string(),
+        forwarded_for: None,
+    }
+}
 
-    let mut now_extensions = fs::read_dir(&e)?
+#[test]
+fn write_an_image() -> Result<()> {
+    let f = Fixture::new()?;
+    let e = &f.storage.image_dir;
+    let config = IngestConfig::default();
+
+    let png = f.store(&config, include_bytes!("test.png"))?;
+    let gif = f.store(&config, include_bytes!("../tests/parrot.gif"))?;
+
+    let mut now_extensions = fs::read_dir(e)?
         .map(|e| {
             e.unwrap()
                 .path()
@@ -40,5 +95,633 @@ fn write_an_image() -> Result<()> {
         "created one of each"
     );
 
+    let conn = f.conn.lock().unwrap();
+    let png = images::get(&conn, &png)?.expect("png recorded");
+    assert_eq!("image/png", png.format);
+    assert_eq!(
+        fs::metadata(f.storage.image_path(&png.id))?.len() as i64,
+        png.size
+    );
+    assert_eq!(
+        images::sha256_hex(&fs::read(f.storage.image_path(&png.id))?),
+        png.sha256
+    );
+
+    let gif = images::get(&conn, &gif)?.expect("gif recorded");
+    assert_eq!("image/gif", gif.original_format);
+    assert_eq!("127.0.0.1:1234", gif.caller_addr);
+
+    Ok(())
+}
+
+#[test]
+fn dedupe() -> Result<()> {
+    let f = Fixture::new()?;
+    let data = include_bytes!("test.png");
+
+    for (dedupe, expect_same) in [
+        (Dedupe::Output, true),
+        (Dedupe::Input, true),
+        (Dedupe::Off, false),
+    ] {
+        let config = IngestConfig {
+            dedupe,
+            ..IngestConfig::default()
+        };
+        let first = f.store(&config, data)?;
+        let second = f.store(&config, data)?;
+        assert_eq!(expect_same, first == second, "{dedupe:?}");
+    }
+
+    // someone deleted it behind our back; we should write it again
+    let config = IngestConfig::default();
+    let first = f.store(&config, data)?;
+    fs::remove_file(f.storage.image_path(&first))?;
+    let second = f.store(&config, data)?;
+    assert!(f.storage.image_path(&second).is_file());
+
+    Ok(())
+}
+
+#[test]
+fn remove() -> Result<()> {
+    let f = Fixture::new()?;
+
+    let image = f.store(&IngestConfig::default(), include_bytes!("test.png"))?;
+    let thumbs = ThumbConfig::parse("thumb:320x160,preview:1024x1024:webp")?;
+    let written = crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &image)?;
+    assert_eq!(2, written.len());
+    let public = crate::gallery::gallery_store(&f.conn, &[1], "foo", "bar", &[image.as_str()])?;
+    let other = f.store(
+        &IngestConfig::default(),
+        include_bytes!("../tests/orient_1.jpg"),
+    )?;
+    let other_thumbs = crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &other)?;
+
+    // everything written for the image goes, even with the preview preset gone since,
+    // and nothing else
+    assert!(images::remove(
+        &f.storage,
+        &ThumbConfig::default(),
+        &f.conn,
+        &image
+    )?);
+    assert!(!f.storage.image_path(&image).exists());
+    for thumb in &written {
+        assert!(!f.storage.image_path(thumb).exists(), "{thumb}");
+    }
+    for thumb in std::iter::once(&other).chain(&other_thumbs) {
+        assert!(f.storage.image_path(thumb).exists(), "{thumb}");
+    }
+    assert!(images::get(&f.conn.lock().unwrap(), &image)?.is_none());
+    assert!(crate::gallery::gallery_list_all(&f.conn.lock().unwrap(), &public)?.is_empty());
+
+    assert!(!images::remove(&f.storage, &thumbs, &f.conn, &image)?);
+
+    Ok(())
+}
+
+#[test]
+fn resized() -> Result<()> {
+    use crate::resize::{cached, resized, Resize};
+
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+
+    let image = f.store(&config, include_bytes!("../tests/orient_1.jpg"))?;
+    let resize = Resize::parse(Some(50), Some(50), Some("cover"), Some("png")).unwrap();
+    assert!(cached(&f.storage, &image, &resize)?.is_none());
+
+    let data = resized(&f.storage, &config, &image, &resize)?;
+    let decoded = image::load_from_memory_with_format(&data, image::ImageFormat::Png)?;
+    assert_eq!((50, 50), (decoded.width(), decoded.height()));
+    assert_eq!(Some(data), cached(&f.storage, &image, &resize)?);
+
+    // there's no room for more, but they're still made
+    let full = IngestConfig {
+        max_resizes: 1,
+        ..IngestConfig::default()
+    };
+    let smaller = Resize::parse(Some(20), None, None, Some("png")).unwrap();
+    let data = resized(&f.storage, &full, &image, &smaller)?;
+    let decoded = image::load_from_memory_with_format(&data, image::ImageFormat::Png)?;
+    assert_eq!(20, decoded.width());
+    assert!(cached(&f.storage, &image, &smaller)?.is_none());
+
+    assert!(images::remove(
+        &f.storage,
+        &ThumbConfig::default(),
+        &f.conn,
+        &image
+    )?);
+    assert!(!f.storage.resize_dir(&image).exists());
+
+    Ok(())
+}
+
+#[test]
+fn thumbnail_presets() -> Result<()> {
+    let f = Fixture::new()?;
+
+    let image = f.store(
+        &IngestConfig::default(),
+        include_bytes!("../tests/orient_1.jpg"),
+    )?;
+    let backfill = |thumbs: &ThumbConfig| -> Result<()> {
+        crate::thumbs::queue_missing_thumbs(&f.storage, thumbs, &f.conn)?;
+        crate::thumbs::run_thumb_jobs(&f.storage, thumbs, &f.conn)?;
+        Ok(())
+    };
+    backfill(&ThumbConfig::default())?;
+    let thumb = f.storage.image_path(&format!("{image}.thumb.jpg"));
+    let original = fs::metadata(&thumb)?.modified()?;
+
+    // a new preset is backfilled, and the existing thumbnail left alone
+    let thumbs = ThumbConfig::parse("thumb:320x160,thumb@2x:640x320:webp:60")?;
+    backfill(&thumbs)?;
+    assert_eq!(original, fs::metadata(&thumb)?.modified()?);
+
+    let retina = fs::read(f.storage.image_path(&format!("{image}.thumb@2x.webp")))?;
+    assert_eq!(image::ImageFormat::WebP, image::guess_format(&retina)?);
+    let retina = image::load_from_memory(&retina)?;
+    let small = image::open(&thumb)?;
+    assert_eq!(2 * small.width(), retina.width());
+
+    Ok(())
+}
+
+#[test]
+fn animated_thumbnails() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+    let thumbs = ThumbConfig::default();
+
+    let parrot = f.store(&config, include_bytes!("../tests/parrot.gif"))?;
+    let still = f.store(&config, include_bytes!("test.png"))?;
+
+    // the limits are for thumbnailing too; the still is written, but not the animation
+    let limited = ThumbConfig {
+        limits: crate::ingest::Limits {
+            max_frames: 2,
+            ..Default::default()
+        },
+        ..ThumbConfig::default()
+    };
+    assert!(crate::thumbs::thumbnail(&f.storage, &limited, &f.conn, &parrot).is_err());
+
+    let names = crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &parrot)?;
+    let animated = format!("{parrot}.thumb.anim.webp");
+    assert_eq!(vec![format!("{parrot}.thumb.jpg"), animated.clone()], names);
+    let data = fs::read(f.storage.image_path(&animated))?;
+    assert!(crate::animation::is_animation(
+        &data,
+        image::ImageFormat::WebP
+    ));
+    let decoded =
+        crate::animation::decode_animation(&data, image::ImageFormat::WebP, &config.limits)?;
+    assert!(decoded.width <= 320 && decoded.height <= 160);
+    assert!(decoded.frames.len() > 1);
+    assert!(decoded.frames.iter().all(|(_, delay)| delay % 200 == 0));
+
+    assert_eq!(
+        1,
+        crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &still)?.len()
+    );
+
+    assert!(images::remove(&f.storage, &thumbs, &f.conn, &parrot)?);
+    assert!(!f.storage.image_path(&animated).exists());
+
+    Ok(())
+}
+
+#[test]
+fn cropped_thumbnails_and_placeholders() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+    let thumbs = ThumbConfig::parse("grid:100x100:crop")?;
+
+    let mut stored = Vec::new();
+    for data in [
+        &include_bytes!("../tests/orient_1.jpg")[..],
+        include_bytes!("../tests/parrot.gif"),
+        include_bytes!("../tests/anim.png"),
+    ] {
+        let image = f.store(&config, data)?;
+        let blurhash = images::blurhash(&f.conn.lock().unwrap(), &image)?;
+        // 4x3 or 3x4 components
+        assert_eq!(Some(28), blurhash.map(|b| b.len()), "{image}");
+        stored.push(image);
+    }
+
+    let names = crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &stored[0])?;
+    let grid = image::open(f.storage.image_path(&names[0]))?;
+    assert_eq!((100, 100), (grid.width(), grid.height()));
+
+    let names = crate::thumbs::thumbnail(&f.storage, &thumbs, &f.conn, &stored[1])?;
+    let data = fs::read(f.storage.image_path(&names[1]))?;
+    let animated =
+        crate::animation::decode_animation(&data, image::ImageFormat::WebP, &config.limits)?;
+    assert_eq!(animated.width, animated.height);
+
+    Ok(())
+}
+
+#[test]
+fn thumbnail_queue() -> Result<()> {
+    use crate::thumbs::{queue_missing_thumbs, run_thumb_jobs};
+
+    let f = Fixture::new()?;
+    let thumbs = ThumbConfig::default();
+
+    let image = f.store(&IngestConfig::default(), include_bytes!("test.png"))?;
+    // e.g. it was truncated while restoring a backup
+    fs::write(f.storage.image_path("e/brokenbrok.png"), b"\x89PNG")?;
+    fs::write(f.storage.image_path("e/notanimage"), b"")?;
+
+    assert_eq!(2, queue_missing_thumbs(&f.storage, &thumbs, &f.conn)?);
+    assert_eq!(2, run_thumb_jobs(&f.storage, &thumbs, &f.conn)?);
+    assert!(f
+        .storage
+        .image_path(&format!("{image}.thumb.jpg"))
+        .is_file());
+
+    // the broken one is only retried later
+    assert_eq!(0, run_thumb_jobs(&f.storage, &thumbs, &f.conn)?);
+    let failures = crate::jobs::failures(&f.conn.lock().unwrap())?;
+    assert_eq!(1, failures.len());
+    assert_eq!(
+        ("e/brokenbrok.png", 1),
+        (failures[0].0.as_str(), failures[0].1)
+    );
+
+    // and isn't queued again while it's waiting
+    assert_eq!(1, queue_missing_thumbs(&f.storage, &thumbs, &f.conn)?);
+    assert_eq!(0, run_thumb_jobs(&f.storage, &thumbs, &f.conn)?);
+
+    Ok(())
+}
+
+#[test]
+fn still_webps_are_only_checked_once() -> Result<()> {
+    use crate::thumbs::{queue_missing_thumbs, run_thumb_jobs};
+
+    let f = Fixture::new()?;
+    let thumbs = ThumbConfig::default();
+    let config = IngestConfig {
+        output: OutputFormat::WebP,
+        ..IngestConfig::default()
+    };
+
+    let image = f.store(&config, include_bytes!("test.png"))?;
+    assert!(image.ends_with(".webp"));
+
+    assert_eq!(1, queue_missing_thumbs(&f.storage, &thumbs, &f.conn)?);
+    assert_eq!(1, run_thumb_jobs(&f.storage, &thumbs, &f.conn)?);
+    assert!(crate::jobs::stills(&f.conn.lock().unwrap())?.contains(&image));
+
+    // stored images never change, so it isn't even read again to find out
+    let animation = crate::animation::decode_animation(
+        include_bytes!("../tests/anim.png"),
+        image::ImageFormat::Png,
+        &config.limits,
+    )?;
+    let animated = crate::animation::encode_webp(&animation, None)?;
+    fs::write(f.storage.image_path(&image), animated)?;
+    assert_eq!(0, queue_missing_thumbs(&f.storage, &thumbs, &f.conn)?);
+
+    Ok(())
+}
+
+#[test]
+fn expiry_survives_dedupe() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+    let data = include_bytes!("test.png");
+
+    let first = store(&f.storage, &config, &f.conn, &uploader(), Some(5_000), data)?;
+    let second = f.store(&config, data)?;
+    assert_eq!(first, second);
+
+    let conn = f.conn.lock().unwrap();
+    assert_eq!(None, images::get(&conn, &first)?.unwrap().expires);
+    assert!(images::expired(&conn, i64::MAX)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn sweep() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+
+    let expiring = store(
+        &f.storage,
+        &config,
+        &f.conn,
+        &uploader(),
+        Some(5_000),
+        include_bytes!("test.png"),
+    )?;
+    let thumbs = crate::thumbs::thumbnail(&f.storage, &ThumbConfig::default(), &f.conn, &expiring)?;
+    let kept = f.store(&config, include_bytes!("../tests/orient_1.jpg"))?;
+
+    let ctx = f.ctx();
+    assert_eq!(0, crate::sweep(&ctx, 4_999)?);
+    assert!(f.storage.image_path(&expiring).exists());
+
+    assert_eq!(1, crate::sweep(&ctx, 5_000)?);
+    for name in std::iter::once(&expiring).chain(&thumbs) {
+        assert!(!f.storage.image_path(name).exists(), "{name}");
+    }
+    assert!(images::get(&f.conn.lock().unwrap(), &expiring)?.is_none());
+    assert!(f.storage.image_path(&kept).exists());
+
+    assert_eq!(0, crate::sweep(&ctx, i64::MAX)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_handler() -> Result<()> {
+    let f = Fixture::new()?;
+    let ctx = f.ctx();
+    let image = f.store(&IngestConfig::default(), include_bytes!("test.png"))?;
+    // as if it was uploaded twice
+    let (first, second) = {
+        let conn = f.conn.lock().unwrap();
+        (
+            images::issue_delete_token(&conn, &image, 1)?,
+            images::issue_delete_token(&conn, &image, 2)?,
+        )
+    };
+
+    let delete = |token: &str| {
+        crate::image_delete(
+            caller(),
+            HeaderMap::new(),
+            State(Arc::clone(&ctx)),
+            Path(image.clone()),
+            Query(crate::DeleteQuery {
+                token: token.to_string(),
+            }),
+        )
+    };
+
+    assert_eq!(StatusCode::FORBIDDEN, delete("nope").await.status());
+    assert!(f.storage.image_path(&image).exists());
+
+    // the other uploader still wants it
+    assert_eq!(StatusCode::NO_CONTENT, delete(&first).await.status());
+    assert!(f.storage.image_path(&image).exists());
+    assert_eq!(StatusCode::FORBIDDEN, delete(&first).await.status());
+
+    assert_eq!(StatusCode::NO_CONTENT, delete(&second).await.status());
+    assert!(!f.storage.image_path(&image).exists());
+    assert!(images::get(&f.conn.lock().unwrap(), &image)?.is_none());
+    assert_eq!(StatusCode::FORBIDDEN, delete(&second).await.status());
+
+    // the token's fine, but the image went some other way
+    let late = images::issue_delete_token(&f.conn.lock().unwrap(), &image, 3)?;
+    assert_eq!(StatusCode::NOT_FOUND, delete(&late).await.status());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn resize_sign_handler() -> Result<()> {
+    let f = Fixture::new()?;
+    let ctx = f.ctx();
+    let image = f.store(&IngestConfig::default(), include_bytes!("test.png"))?;
+    let token = images::issue_delete_token(&f.conn.lock().unwrap(), &image, 1)?;
+
+    let sign = |token: Option<&str>| {
+        crate::resize_sign(
+            caller(),
+            HeaderMap::new(),
+            State(Arc::clone(&ctx)),
+            Path(image.clone()),
+            Query(crate::ResizeQuery {
+                w: Some(50),
+                h: None,
+                fit: None,
+                fmt: None,
+                sig: None,
+                token: token.map(str::to_string),
+            }),
+        )
+    };
+
+    assert_eq!(StatusCode::FORBIDDEN, sign(None).await.0);
+    assert_eq!(StatusCode::FORBIDDEN, sign(Some("nope")).await.0);
+
+    let (status, body) = sign(Some(&token)).await;
+    assert_eq!(StatusCode::OK, status);
+    let resize = crate::resize::Resize::parse(Some(50), None, None, None).unwrap();
+    assert_eq!(
+        crate::resize::signed_url(&ctx.secret, &image, &resize),
+        body.0["data"]["id"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn webp_output() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig {
+        output: OutputFormat::WebP,
+        ..IngestConfig::default()
+    };
+
+    for input in [
+        &include_bytes!("test.png")[..],
+        &include_bytes!("../tests/orient_1.jpg")[..],
+    ] {
+        let image = f.store(&config, input)?;
+        assert!(image.ends_with(".webp"), "{image}");
+        assert!(crate::is_image_id(&image));
+
+        let written = fs::read(f.storage.image_path(&image))?;
+        assert_eq!(
+            image::ImageFormat::WebP,
+            image::guess_format(&written)?,
+            "{image}"
+        );
+        crate::thumbs::thumbnail(&f.storage, &ThumbConfig::default(), &f.conn, &image)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn oversized_keeps_alpha() -> Result<()> {
+    use image::GenericImageView;
+
+    let f = Fixture::new()?;
+    let config = IngestConfig {
+        max_lossless_size: 1024,
+        ..IngestConfig::default()
+    };
+
+    let image = f.store(&config, include_bytes!("../tests/alpha.png"))?;
+    assert!(image.ends_with(".webp"), "{image}");
+
+    let written = image::load_from_memory(&fs::read(f.storage.image_path(&image))?)?;
+    assert!(written.color().has_alpha());
+    assert_eq!(0, written.get_pixel(0, 0)[3], "corner is transparent");
+    assert_eq!(255, written.get_pixel(48, 32)[3], "middle is opaque");
+
+    // an alpha channel alone isn't enough to dodge jpeg
+    let opaque = {
+        let mut im = image::load_from_memory(include_bytes!("../tests/alpha.png"))?.into_rgba8();
+        im.pixels_mut().for_each(|p| p[3] = 255);
+        let mut out = Vec::new();
+        im.write_to(&mut std::io::Cursor::new(&mut out), image::ImageFormat::Png)?;
+        out
+    };
+    let image = f.store(&config, &opaque)?;
+    assert!(image.ends_with(".jpg"), "{image}");
+
+    Ok(())
+}
+
+#[test]
+fn animated_webp() -> Result<()> {
+    let f = Fixture::new()?;
+    let parrot = include_bytes!("../tests/parrot.gif");
+
+    let image = f.store(&IngestConfig::default(), parrot)?;
+    assert!(image.ends_with(".gif"), "{image}");
+
+    let config = IngestConfig {
+        animated_webp: true,
+        ..IngestConfig::default()
+    };
+    let image = f.store(&config, parrot)?;
+    assert!(image.ends_with(".webp"), "{image}");
+    assert!(crate::is_image_id(&image));
+
+    let record = images::get(&f.conn.lock().unwrap(), &image)?.expect("recorded");
+    assert_eq!("image/gif", record.original_format);
+    assert_eq!("image/webp", record.format);
+
+    crate::thumbs::thumbnail(&f.storage, &ThumbConfig::default(), &f.conn, &image)?;
+    Ok(())
+}
+
+#[test]
+fn stays_animated() -> Result<()> {
+    let f = Fixture::new()?;
+    let config = IngestConfig::default();
+
+    let parrot =
+        crate::animation::gif_animation(include_bytes!("../tests/parrot.gif"), &config.limits)?
+            .expect("small");
+    let webp = crate::animation::encode_webp(&parrot, None)?;
+
+    for input in [&include_bytes!("../tests/anim.png")[..], &webp[..]] {
+        let image = f.store(&config, input)?;
+        assert!(crate::is_image_id(&image));
+
+        let written = fs::read(f.storage.image_path(&image))?;
+        match image::guess_format(&written)? {
+            image::ImageFormat::Gif => assert!(image.ends_with(".gif"), "{image}"),
+            format => {
+                assert_eq!(image::ImageFormat::WebP, format, "{image}");
+                assert!(crate::animation::is_animation(&written, format), "{image}");
+            }
+        }
+        crate::thumbs::thumbnail(&f.storage, &ThumbConfig::default(), &f.conn, &image)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn colour_profiles() -> Result<()> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::PngEncoder;
+    use image::{ImageDecoder, ImageEncoder};
+
+    let f = Fixture::new()?;
+
+    let p3 = moxcms::ColorProfile::new_display_p3()
+        .encode()
+        .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+    // with a bit of noise, so the jpeg is recognisable after it's been through the encoder
+    let pixels =
+        image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([200, 60 + (x ^ y) as u8 % 4, 60]));
+
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    encoder.set_icc_profile(p3.clone())?;
+    pixels.write_with_encoder(encoder)?;
+
+    let mut jpeg = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, 95);
+    encoder.set_icc_profile(p3.clone())?;
+    pixels.write_with_encoder(encoder)?;
+
+    let stored = |config: &IngestConfig, input: &[u8]| -> Result<(Option<Vec<u8>>, [u8; 3])> {
+        let image = store(&f.storage, config, &f.conn, &uploader(), None, input)?;
+        let written = fs::read(f.storage.image_path(&image))?;
+        let mut decoder = image::ImageReader::new(std::io::Cursor::new(&written))
+            .with_guessed_format()?
+            .into_decoder()?;
+        let icc = decoder.icc_profile()?;
+        let image = image::DynamicImage::from_decoder(decoder)?;
+        Ok((icc, image.to_rgb8().get_pixel(0, 0).0))
+    };
+
+    let embed = IngestConfig {
+        icc_profiles: IccProfiles::Embed,
+        ..IngestConfig::default()
+    };
+    let embed_webp = IngestConfig {
+        output: OutputFormat::WebP,
+        ..embed.clone()
+    };
+
+    for input in [&png, &jpeg] {
+        let (icc, [r, _, _]) = stored(&IngestConfig::default(), input)?;
+        assert_eq!(None, icc);
+        assert!(r > 205, "converted to srgb: {r}");
+
+        for config in [&embed, &embed_webp] {
+            let (icc, [r, _, _]) = stored(config, input)?;
+            assert_eq!(Some(&p3), icc.as_ref());
+            assert!(r.abs_diff(200) <= 2, "left alone: {r}");
+        }
+    }
+
+    // a grey profile can't go on an rgb image, so it's converted, even when embedding
+    let grey = moxcms::ColorProfile::new_gray_with_gamma(1.8)
+        .encode()
+        .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    encoder.set_icc_profile(grey)?;
+    image::GrayImage::from_fn(64, 64, |x, y| image::Luma([100 + (x ^ y) as u8 % 4]))
+        .write_with_encoder(encoder)?;
+    for config in [&embed, &embed_webp] {
+        let (icc, _) = stored(config, &png)?;
+        assert_eq!(None, icc);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn strips_exif() -> Result<()> {
+    let f = Fixture::new()?;
+    let input = include_bytes!("../tests/orient_6.jpg");
+    assert!(exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(&input[..]))
+        .is_ok());
+
+    let image = f.store(&IngestConfig::default(), input)?;
+    let written = fs::read(f.storage.image_path(&image))?;
+    assert!(exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(&written))
+        .is_err());
     Ok(())
 }
@@ -1,69 +1,548 @@
+use std::env;
 use std::fs;
-use std::io::{BufWriter, Read};
-use std::path::Path;
+use std::io::{BufWriter, Read, Write};
+use std::ops::ControlFlow;
+use std::sync::Mutex;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use image::codecs::jpeg::JpegEncoder;
-use rayon::prelude::*;
+use image::{DynamicImage, ImageFormat};
+use rusqlite::Connection;
 
-fn thumb_name(image_id: &str) -> String {
-    format!("{}.thumb.jpg", image_id)
+use crate::animation::Animation;
+use crate::ingest::Limits;
+use crate::storage::StorageConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbFormat {
+    Jpeg,
+    /// lossy, but keeps transparency
+    WebP,
+}
+
+impl ThumbFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "jpg",
+            ThumbFormat::WebP => "webp",
+        }
+    }
+}
+
+/// One size of thumbnail; the image is shrunk to fit inside `width` x `height`,
+/// keeping its aspect ratio, and stored next to it as `{image_id}.{name}.{ext}`.
+/// With `crop`, it's exactly that shape instead, for grids.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThumbPreset {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: ThumbFormat,
+    /// 1-100
+    pub quality: u8,
+    /// fill the whole size, cropping off the least interesting part of the image
+    pub crop: bool,
+}
+
+impl ThumbPreset {
+    /// `e/abcdefghij.png` -> `e/abcdefghij.png.thumb.jpg`
+    pub fn thumb_name(&self, image_id: &str) -> String {
+        format!("{image_id}.{}.{}", self.name, self.format.extension())
+    }
+}
+
+/// low, as thumbnails are small, and there are a lot of them on a page
+const DEFAULT_QUALITY: u8 = 40;
+
+/// 5fps, which is plenty to show that something's moving
+const ANIMATED_FRAME_MS: u32 = 200;
+/// five seconds; the rest is left for the full image
+const ANIMATED_MAX_FRAMES: usize = 25;
+const ANIMATED_QUALITY: f32 = 40.;
+
+#[derive(Clone, Debug)]
+pub struct ThumbConfig {
+    pub presets: Vec<ThumbPreset>,
+    /// also write a short, animated webp version of the first preset, for animated images
+    pub animated: bool,
+    /// what decoding an animation may take; the same as for uploads
+    pub limits: Limits,
+}
+
+impl Default for ThumbConfig {
+    /// what the frontend expects: `{image_id}.thumb.jpg`
+    fn default() -> ThumbConfig {
+        ThumbConfig {
+            presets: vec![ThumbPreset {
+                name: "thumb".to_string(),
+                width: 320,
+                height: 160,
+                format: ThumbFormat::Jpeg,
+                quality: DEFAULT_QUALITY,
+                crop: false,
+            }],
+            animated: true,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ThumbConfig {
+    /// `THUMBNAILS`: see `parse`; default: `thumb:320x160:jpg:40`
+    /// `ANIMATED_THUMBNAILS`: `true` (the default) or `false`
+    pub fn from_env() -> Result<ThumbConfig> {
+        let mut config = match env::var("THUMBNAILS") {
+            Ok(val) => ThumbConfig::parse(&val).with_context(|| anyhow!("invalid THUMBNAILS"))?,
+            Err(_) => ThumbConfig::default(),
+        };
+        if let Ok(val) = env::var("ANIMATED_THUMBNAILS") {
+            config.animated = val
+                .parse()
+                .with_context(|| anyhow!("invalid ANIMATED_THUMBNAILS: {val:?}"))?;
+        }
+        Ok(config)
+    }
+
+    /// comma separated `name:{width}x{height}[:jpg|webp[:quality]][:crop]`,
+    /// e.g. `thumb:320x160,thumb@2x:640x320,preview:1024x1024:webp:60,grid:200x200:crop`
+    pub fn parse(spec: &str) -> Result<ThumbConfig> {
+        let mut presets: Vec<ThumbPreset> = Vec::new();
+        for preset in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut parts = preset.split(':').collect::<Vec<_>>();
+            let crop = parts.len() > 2 && parts.last() == Some(&"crop");
+            if crop {
+                parts.pop();
+            }
+            let (name, size, format, quality) = match parts.as_slice() {
+                [name, size] => (*name, *size, "jpg", None),
+                [name, size, format] => (*name, *size, *format, None),
+                [name, size, format, quality] => (*name, *size, *format, Some(*quality)),
+                _ => bail!("{preset:?}: try e.g. 'thumb:320x160:jpg:40'"),
+            };
+
+            // it ends up in file names and urls
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "@_-".contains(c))
+            {
+                bail!("{preset:?}: names may only contain letters, numbers, '@', '_' and '-'");
+            }
+            if presets.iter().any(|p| p.name == name) {
+                bail!("{preset:?}: duplicate name");
+            }
+
+            let (width, height) = size
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+                .filter(|&(w, h)| w > 0 && h > 0)
+                .ok_or_else(|| anyhow!("{preset:?}: invalid size, try e.g. '320x160'"))?;
+
+            let format = match format {
+                "jpg" | "jpeg" => ThumbFormat::Jpeg,
+                "webp" => ThumbFormat::WebP,
+                _ => bail!("{preset:?}: invalid format, try 'jpg' or 'webp'"),
+            };
+
+            let quality = match quality {
+                Some(quality) => quality
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|q| (1..=100).contains(q))
+                    .ok_or_else(|| anyhow!("{preset:?}: invalid quality, try 1-100"))?,
+                None => DEFAULT_QUALITY,
+            };
+
+            presets.push(ThumbPreset {
+                name: name.to_string(),
+                width,
+                height,
+                format,
+                quality,
+                crop,
+            });
+        }
+
+        if presets.is_empty() {
+            bail!("at least one thumbnail size is needed");
+        }
+
+        Ok(ThumbConfig {
+            presets,
+            ..ThumbConfig::default()
+        })
+    }
+
+    /// every still thumbnail this image should have, whether or not it exists yet
+    pub fn thumb_names(&self, image_id: &str) -> Vec<String> {
+        self.presets
+            .iter()
+            .map(|preset| preset.thumb_name(image_id))
+            .collect()
+    }
+
+    /// `e/abcdefghij.gif` -> `e/abcdefghij.gif.thumb.anim.webp`, which only exists for
+    /// animated images; this doesn't check `animated`, as it might have been set previously
+    pub fn animated_name(&self, image_id: &str) -> Option<String> {
+        let preset = self.presets.first()?;
+        Some(format!("{image_id}.{}.anim.webp", preset.name))
+    }
+
+    /// the animated thumbnail, and the image's format, if it's wanted and hasn't been written;
+    /// the image might turn out not to be animated
+    fn animated_missing(
+        &self,
+        storage: &StorageConfig,
+        image_id: &str,
+    ) -> Option<(String, ImageFormat)> {
+        if !self.animated {
+            return None;
+        }
+        // we store animations as gifs or webps
+        let format = match image_id.rsplit_once('.')?.1 {
+            "gif" => ImageFormat::Gif,
+            "webp" => ImageFormat::WebP,
+            _ => return None,
+        };
+        let name = self.animated_name(image_id)?;
+        if storage.image_path(&name).is_file() {
+            return None;
+        }
+        Some((name, format))
+    }
 }
 
-pub fn generate_all_thumbs() -> Result<()> {
+/// Queue up anything missing a thumbnail, including for presets added since
+/// it was uploaded. Thumbnails for presets which have since been removed are left
+/// alone, until the image is.
+pub fn queue_missing_thumbs(
+    storage: &StorageConfig,
+    thumbs: &ThumbConfig,
+    conn: &Mutex<Connection>,
+) -> Result<usize> {
     let mut needed = Vec::with_capacity(100);
+    let mut found_still = Vec::new();
+    let stills = crate::jobs::stills(&*conn.lock().map_err(|_| anyhow!("poison"))?)?;
 
-    for path in Path::new("e").read_dir()? {
+    for path in storage.image_dir.read_dir()? {
         let path = path?;
 
-        if let Some(s) = path.path().to_str() {
-            if !crate::is_image_id(s) {
+        if let Some(s) = storage.image_id(&path.path()) {
+            if !crate::is_image_id(&s) {
                 continue;
             }
 
-            if Path::new(&thumb_name(s)).is_file() {
+            let stills_done = thumbs
+                .thumb_names(&s)
+                .iter()
+                .all(|name| storage.image_path(name).is_file());
+
+            if stills_done {
+                match thumbs.animated_missing(storage, &s) {
+                    None => continue,
+                    Some(_) if stills.contains(&s) => continue,
+                    Some((_, format)) => {
+                        let data = fs::read(path.path())?;
+                        if !crate::animation::is_animation(&data, format) {
+                            found_still.push(s);
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            needed.push(s);
+        }
+    }
+
+    let mut conn = conn.lock().map_err(|_| anyhow!("poison"))?;
+    let tx = conn.transaction()?;
+    let now = crate::gallery::epoch_millis();
+    for image_id in &needed {
+        crate::jobs::enqueue(&tx, image_id, now)?;
+    }
+    for image_id in &found_still {
+        crate::jobs::still(&tx, image_id)?;
+    }
+    tx.commit()?;
+
+    Ok(needed.len())
+}
+
+/// Thumbnail everything that's queued, and due, a few at a time, so the database
+/// isn't locked for long. Failures are recorded, to be retried later.
+/// Returns how many images were attempted.
+pub fn run_thumb_jobs(
+    storage: &StorageConfig,
+    thumbs: &ThumbConfig,
+    conn: &Mutex<Connection>,
+) -> Result<usize> {
+    let lock = || conn.lock().map_err(|_| anyhow!("poison"));
+    let mut attempted = 0;
+    loop {
+        let due = crate::jobs::due(&*lock()?, crate::gallery::epoch_millis(), 16)?;
+        if due.is_empty() {
+            return Ok(attempted);
+        }
+
+        for image_id in due {
+            attempted += 1;
+
+            // it's been deleted since
+            if !storage.image_path(&image_id).is_file() {
+                crate::jobs::succeeded(&*lock()?, &image_id)?;
                 continue;
             }
 
-            needed.push(s.to_string());
+            match thumbnail(storage, thumbs, conn, &image_id) {
+                Ok(_) => crate::jobs::succeeded(&*lock()?, &image_id)?,
+                Err(e) => {
+                    println!("thumbnailing {image_id:?} failed: {e:?}");
+                    let now = crate::gallery::epoch_millis();
+                    crate::jobs::failed(&*lock()?, &image_id, &format!("{e:#}"), now)?;
+                }
+            }
         }
     }
+}
 
-    println!("thumbnailing {} image(s)", needed.len());
+/// Write any of the image's thumbnails which don't exist yet;
+/// returns all of their names, including the animated one, if there is one.
+pub fn thumbnail(
+    storage: &StorageConfig,
+    thumbs: &ThumbConfig,
+    conn: &Mutex<Connection>,
+    image_id: &str,
+) -> Result<Vec<String>> {
+    // e.g. a duplicate upload
+    let missing = thumbs
+        .presets
+        .iter()
+        .filter(|preset| !storage.image_path(&preset.thumb_name(image_id)).is_file())
+        .collect::<Vec<_>>();
+    let animated = thumbs.animated_missing(storage, image_id);
 
-    needed
-        .par_iter()
-        .map(|path| thumbnail(path).with_context(|| anyhow!("thumbnailing {:?}", path)))
-        .collect::<Result<Vec<_>, _>>()?;
+    if !missing.is_empty() || animated.is_some() {
+        // before they're written, so even a partly thumbnailed image can be removed
+        let mut names = missing
+            .iter()
+            .map(|preset| preset.thumb_name(image_id))
+            .collect::<Vec<_>>();
+        names.extend(animated.iter().map(|(name, _)| name.clone()));
+        crate::images::record_derivatives(
+            &*conn.lock().map_err(|_| anyhow!("poison"))?,
+            image_id,
+            &names,
+        )?;
 
-    println!("thumbnailing complete");
+        let mut bytes = Vec::with_capacity(1_000_000);
+        fs::File::open(storage.image_path(image_id))?.read_to_end(&mut bytes)?;
 
-    Ok(())
+        if !missing.is_empty() {
+            // the thumbnail has no profile, so it has to be sRGB
+            let image = crate::colour::load_srgb(&bytes, &thumbs.limits)?;
+
+            for preset in missing {
+                write_thumb(storage, preset, image_id, &image)
+                    .with_context(|| anyhow!("writing {:?} thumbnail", preset.name))?;
+            }
+        }
+
+        if let (Some((name, format)), Some(preset)) = (animated, thumbs.presets.first()) {
+            if crate::animation::is_animation(&bytes, format) {
+                write_animated_thumb(storage, preset, &thumbs.limits, &name, &bytes, format)
+                    .with_context(|| anyhow!("writing animated thumbnail"))?;
+            } else {
+                crate::jobs::still(&*conn.lock().map_err(|_| anyhow!("poison"))?, image_id)?;
+            }
+        }
+    }
+
+    let mut names = thumbs.thumb_names(image_id);
+    names.extend(
+        thumbs
+            .animated_name(image_id)
+            .filter(|name| storage.image_path(name).is_file()),
+    );
+    Ok(names)
 }
 
-pub fn thumbnail(image_id: &str) -> Result<String> {
-    let thumb_name = thumb_name(image_id);
+/// A [BlurHash](https://blurha.sh) of the image, a couple of dozen characters
+/// which clients can draw as a blurry placeholder, before the thumbnail has loaded.
+pub fn blurhash(image: &DynamicImage) -> Result<String> {
+    // it's all blurred away anyway, and the encoder is slow on big images
+    let small = image.thumbnail(64, 64).into_rgba8();
+    let (x, y) = if small.width() >= small.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+    blurhash::encode(x, y, small.width(), small.height(), small.as_raw())
+        .map_err(|e| anyhow!("blurhash: {e:?}"))
+}
+
+/// A few seconds of the animation, at a low frame rate, at the preset's size.
+fn write_animated_thumb(
+    storage: &StorageConfig,
+    preset: &ThumbPreset,
+    limits: &Limits,
+    name: &str,
+    data: &[u8],
+    format: ImageFormat,
+) -> Result<()> {
+    let mut frames: Vec<(image::RgbaImage, u32)> = Vec::new();
+    let mut elapsed = 0u32;
+    let mut next_sample = 0u32;
+    // picked on the first frame, so the crop doesn't wander around
+    let mut window = None;
+
+    crate::animation::each_frame(data, format, limits, |frame, delay| {
+        // what browsers do with silly delays
+        let delay = if delay < 20 { 100 } else { delay };
+        let end = elapsed.saturating_add(delay);
+        elapsed = end;
+
+        // take whatever's showing at each sample time; slow frames cover several samples
+        let mut sampled = false;
+        while next_sample < end {
+            if sampled {
+                frames.last_mut().expect("just pushed").1 += ANIMATED_FRAME_MS;
+            } else if frames.len() < ANIMATED_MAX_FRAMES {
+                let frame = DynamicImage::ImageRgba8(frame.clone());
+                let shrunk = if preset.crop {
+                    let window = *window.get_or_insert_with(|| {
+                        crate::crop::busiest_window(&frame, preset.width, preset.height)
+                    });
+                    crate::crop::crop_to(&frame, window, preset.width, preset.height)
+                } else {
+                    frame.thumbnail(preset.width, preset.height)
+                }
+                .into_rgba8();
+                frames.push((shrunk, ANIMATED_FRAME_MS));
+                sampled = true;
+            } else {
+                return ControlFlow::Break(());
+            }
+            next_sample += ANIMATED_FRAME_MS;
+        }
+        ControlFlow::Continue(())
+    })?;
 
-    let mut bytes = Vec::with_capacity(1_000_000);
-    fs::File::open(image_id)?.read_to_end(&mut bytes)?;
+    // it's all over before the second sample, so it'd look like a still anyway
+    let Some((first, _)) = frames.first().filter(|_| frames.len() > 1) else {
+        return Ok(());
+    };
 
-    let image = image::load_from_memory(&bytes)?;
-    let shrunk = image.thumbnail(320, 160);
-    let shrunk = shrunk.into_rgb8();
+    let animation = Animation {
+        width: first.width(),
+        height: first.height(),
+        frames,
+        plays: None,
+    };
+    let encoded = crate::animation::encode_webp(&animation, Some(ANIMATED_QUALITY))?;
 
-    let temp = tempfile_fast::PersistableTempFile::new_in("e")?;
+    let path = storage.image_path(name);
+    let mut temp = tempfile_fast::PersistableTempFile::new_in(&storage.image_dir)?;
+    temp.write_all(&encoded)?;
+    temp.persist_noclobber(&path).map_err(|e| e.error)?;
+    crate::ingest::make_readable(&path)?;
+
+    Ok(())
+}
+
+fn write_thumb(
+    storage: &StorageConfig,
+    preset: &ThumbPreset,
+    image_id: &str,
+    image: &DynamicImage,
+) -> Result<()> {
+    let thumb_path = storage.image_path(&preset.thumb_name(image_id));
+    let shrunk = if preset.crop {
+        crate::crop::smart_crop(image, preset.width, preset.height)
+    } else {
+        image.thumbnail(preset.width, preset.height)
+    };
+
+    let temp = tempfile_fast::PersistableTempFile::new_in(&storage.image_dir)?;
     let mut buf = BufWriter::new(temp);
 
-    let encoder = JpegEncoder::new_with_quality(&mut buf, 40);
-    shrunk.write_with_encoder(encoder)?;
+    match preset.format {
+        ThumbFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut buf, preset.quality);
+            shrunk.into_rgb8().write_with_encoder(encoder)?;
+        }
+        ThumbFormat::WebP => {
+            let (width, height) = (shrunk.width(), shrunk.height());
+            let quality = f32::from(preset.quality);
+            let encoded = if shrunk.color().has_alpha() {
+                let rgba = shrunk.into_rgba8();
+                webp::Encoder::from_rgba(&rgba, width, height).encode_simple(false, quality)
+            } else {
+                let rgb = shrunk.into_rgb8();
+                webp::Encoder::from_rgb(&rgb, width, height).encode_simple(false, quality)
+            }
+            .map_err(|e| anyhow!("encoding webp: {e:?}"))?;
+            buf.write_all(&encoded)?;
+        }
+    }
 
     // into_inner() is documented to flush
     let temp = buf.into_inner()?;
 
-    temp.persist_noclobber(&thumb_name).map_err(|e| e.error)?;
-    crate::ingest::make_readable(&thumb_name)?;
+    temp.persist_noclobber(&thumb_path).map_err(|e| e.error)?;
+    crate::ingest::make_readable(&thumb_path)?;
+
+    Ok(())
+}
 
-    Ok(thumb_name)
+#[cfg(test)]
+mod tests {
+    use super::{ThumbConfig, ThumbFormat};
+
+    #[test]
+    fn parse() {
+        let config =
+            ThumbConfig::parse("thumb:320x160, thumb@2x:640x320:jpg:60,preview:1024x768:webp")
+                .unwrap();
+        assert_eq!(ThumbConfig::default().presets[..], config.presets[..1]);
+        assert_eq!(60, config.presets[1].quality);
+        assert_eq!(ThumbFormat::WebP, config.presets[2].format);
+        assert_eq!(
+            (1024, 768),
+            (config.presets[2].width, config.presets[2].height)
+        );
+        assert_eq!(
+            vec![
+                "e/abcdefghij.png.thumb.jpg",
+                "e/abcdefghij.png.thumb@2x.jpg",
+                "e/abcdefghij.png.preview.webp",
+            ],
+            config.thumb_names("e/abcdefghij.png")
+        );
+        assert!(!config.presets.iter().any(|p| p.crop));
+
+        let config = ThumbConfig::parse("grid:200x200:crop,big:800x600:webp:60:crop").unwrap();
+        assert!(config.presets.iter().all(|p| p.crop));
+        assert_eq!(ThumbFormat::WebP, config.presets[1].format);
+        assert_eq!(60, config.presets[1].quality);
+
+        for bad in [
+            "",
+            "thumb",
+            "thumb:320",
+            "thumb:0x160",
+            "thumb:320x160:png",
+            "thumb:320x160:jpg:0",
+            "thumb:320x160:jpg:40:extra",
+            "thumb:crop",
+            "thumb:320x160:crop:jpg",
+            "../thumb:320x160",
+            "thumb:320x160,thumb:640x320",
+        ] {
+            assert!(ThumbConfig::parse(bad).is_err(), "{bad:?}");
+        }
+    }
 }
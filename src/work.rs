@@ -0,0 +1,110 @@
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use tokio::sync::Semaphore;
+
+/// Image decoding and encoding is slow and synchronous, so it's run on
+/// the blocking pool, with at most `concurrency` jobs at once, and at most
+/// `queue` more waiting for a slot. Anything beyond that is turned away.
+#[derive(Clone)]
+pub struct WorkPool {
+    running: Arc<Semaphore>,
+    admitted: Arc<Semaphore>,
+}
+
+/// returned (inside an `anyhow::Error`) when the queue is full
+#[derive(Debug)]
+pub struct Saturated;
+
+impl fmt::Display for Saturated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too much work queued")
+    }
+}
+
+impl std::error::Error for Saturated {}
+
+impl WorkPool {
+    pub fn new(concurrency: usize, queue: usize) -> WorkPool {
+        let concurrency = concurrency.max(1);
+        WorkPool {
+            running: Arc::new(Semaphore::new(concurrency)),
+            admitted: Arc::new(Semaphore::new(concurrency + queue)),
+        }
+    }
+
+    /// `WORK_CONCURRENCY` (default: the number of cpus) and `WORK_QUEUE` (default: 32)
+    pub fn from_env() -> Result<WorkPool> {
+        let concurrency = match env::var("WORK_CONCURRENCY") {
+            Ok(val) => val
+                .parse()
+                .with_context(|| anyhow!("invalid WORK_CONCURRENCY: {val:?}"))?,
+            Err(_) => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let queue = match env::var("WORK_QUEUE") {
+            Ok(val) => val
+                .parse()
+                .with_context(|| anyhow!("invalid WORK_QUEUE: {val:?}"))?,
+            Err(_) => 32,
+        };
+        Ok(WorkPool::new(concurrency, queue))
+    }
+
+    pub async fn run<T, F>(&self, work: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let admitted = Arc::clone(&self.admitted)
+            .try_acquire_owned()
+            .map_err(|_| Saturated)?;
+        let running = Arc::clone(&self.running)
+            .acquire_owned()
+            .await
+            .expect("semaphore never closed");
+
+        // the permits move into the job, so they're held until it's actually finished,
+        // even if the request that started it goes away
+        tokio::task::spawn_blocking(move || {
+            let _permits = (admitted, running);
+            work()
+        })
+        .await
+        .with_context(|| anyhow!("joining work"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{Saturated, WorkPool};
+
+    #[tokio::test]
+    async fn saturation() {
+        let pool = WorkPool::new(1, 0);
+        let (tx, rx) = mpsc::channel::<()>();
+
+        let busy = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || Ok(rx.recv()?)).await }
+        });
+
+        // wait for the first job to actually take the slot
+        while pool.admitted.available_permits() != 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let err = pool.run(|| Ok(())).await.unwrap_err();
+        assert!(err.downcast_ref::<Saturated>().is_some());
+
+        tx.send(()).unwrap();
+        busy.await.unwrap().unwrap();
+        assert_eq!(5, pool.run(|| Ok(5)).await.unwrap());
+    }
+}
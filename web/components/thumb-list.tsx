@@ -22,6 +22,9 @@ export function ThumbList(props: ThumbProps) {
             return (
               <ThumbDone
                 bare={item.base}
+                animated={item.animated}
+                caption={item.caption}
+                alt={item.alt}
                 stats={item.stats}
                 picking={
                   props.picking?.v
@@ -43,6 +46,10 @@ export function ThumbList(props: ThumbProps) {
 
 interface ThumbDoneProps {
   bare: ImageId;
+  /** there's an animated thumbnail, too */
+  animated?: boolean;
+  caption?: string;
+  alt?: string;
   /** only present for uploads from this session */
   stats?: UploadStats;
   picking?: boolean;
@@ -51,6 +58,8 @@ interface ThumbDoneProps {
 
 export function ThumbDone(props: ThumbDoneProps) {
   const [copied, setCopied] = useState<boolean>(false);
+  // thumbnails are made in the background, so might not be there yet
+  const [pending, setPending] = useState<boolean>(false);
 
   const doCopy = async () => {
     try {
@@ -105,9 +114,25 @@ export function ThumbDone(props: ThumbDoneProps) {
   }
   return (
     <li>
-      <a href={bare} target={'_blank'} class={'thumb--frame-imgbox'}>
-        <img src={`${bare}.thumb.jpg`} loading={'lazy'} />
+      <a
+        href={bare}
+        target={'_blank'}
+        class={`thumb--frame-imgbox${pending ? ' thumb--frame-message' : ''}`}
+      >
+        {pending ? (
+          <span>thumbnail not ready yet</span>
+        ) : (
+          <img
+            src={
+              props.animated ? `${bare}.thumb.anim.webp` : `${bare}.thumb.jpg`
+            }
+            alt={props.alt}
+            loading={'lazy'}
+            onError={() => setPending(true)}
+          />
+        )}
       </a>
+      {props.caption && <div class={'thumb--caption'}>{props.caption}</div>}
       {props.stats && <StatsLine stats={props.stats} />}
       {footer}
     </li>
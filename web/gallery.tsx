@@ -36,7 +36,7 @@ export function Gallery() {
       <div class={'alert alert-danger'}>loading failed: {error?.message}</div>
     );
   }
-  if (status !== 'success' || !Array.isArray(data)) {
+  if (status !== 'success' || !data) {
     return <div class={'alert alert-danger'}>loading failed: invalid data</div>;
   }
 
@@ -44,17 +44,29 @@ export function Gallery() {
     <div class={'container-fluid'}>
       <div class={'row gallery--header'}>
         <div class={'col'}>
-          Gallery: <a href={`/gallery/#${pub}`}>{pub}</a>
+          {data.attributes.title ?? 'Gallery'}:{' '}
+          <a href={`/gallery/#${pub}`}>{pub}</a>
           <span className={'home--sign_in-divider'}>|</span>
           <a href={'/'}>back to home</a>
         </div>
       </div>
+      {data.attributes.description && (
+        <div class={'row'}>
+          <div class={'col text-body-secondary'}>
+            {data.attributes.description}
+          </div>
+        </div>
+      )}
       <div class={'row'}>
         <div class={'col'}>
           <ThumbList
-            items={data.map(({ id }) => ({
+            items={data.images.map(({ id, attributes, meta }) => ({
               state: 'done',
               base: `../${id}`,
+              animated: meta?.animated,
+              blurhash: meta?.blurhash,
+              caption: attributes?.caption ?? undefined,
+              alt: attributes?.alt ?? undefined,
               ctx: 'gallery',
             }))}
           />
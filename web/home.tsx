@@ -32,7 +32,15 @@ export type PendingItem = { ctx: string; stats?: UploadStats } & (
   | { state: 'ready'; file: OurFile }
   | { state: 'starting'; file: OurFile }
   | { state: 'uploading'; progress: number; file: OurFile }
-  | { state: 'done'; base: string }
+  | {
+      state: 'done';
+      base: string;
+      animated?: boolean;
+      blurhash?: string;
+      /** set by the gallery's owner */
+      caption?: string;
+      alt?: string;
+    }
   | { state: 'error'; error: string; file: OurFile }
 );
 
@@ -1,16 +1,34 @@
 import { PendingItem } from '../home';
 import * as z from 'zod/mini';
 
-export async function getGallery(
-  gallery: string,
-): Promise<GalleryResponse['data']> {
+export type GalleryImage = z.infer<typeof galleryImageSchema>;
+
+export interface GalleryDetails {
+  attributes: GalleryResponse['data']['attributes'];
+  cover: string | undefined;
+  /** in the gallery's order */
+  images: GalleryImage[];
+}
+
+export async function getGallery(gallery: string): Promise<GalleryDetails> {
   const resp = await fetch(`/api/gallery/${gallery}`);
   const body: unknown = await resp.json();
   if (!isGalleryResponse(body)) {
     throw new Error(`missing data in response: ${JSON.stringify(body)}`);
   }
 
-  return body.data;
+  const { attributes, relationships } = body.data;
+  const byId = new Map(body.included.map((image) => [image.id, image]));
+  const images = relationships.images.data.flatMap(({ id }) => {
+    const image = byId.get(id);
+    return image ? [image] : [];
+  });
+
+  return {
+    attributes,
+    cover: relationships.cover.data?.id,
+    images,
+  };
 }
 
 export async function putGalleryResp(gallery: string, images: string[]) {
@@ -109,6 +127,8 @@ export async function driveUpload(
     state: 'done',
     ctx: initial.ctx,
     base,
+    animated: response.data.meta?.animated === true,
+    blurhash: response.data.meta?.blurhash,
     stats: initial.stats,
   } as const;
 }
@@ -122,13 +142,39 @@ const resourceObjectResponseSchema = z.object({
   data: resourceObjectSchema,
 });
 
-const galleryResponseSchema = z.object({
-  data: z.array(
+const galleryImageSchema = z.object({
+  id: z.string(),
+  type: z.literal('image'),
+  attributes: z.optional(
     z.object({
-      id: z.string(),
-      type: z.literal('image'),
+      caption: z.nullable(z.string()),
+      alt: z.nullable(z.string()),
     }),
   ),
+  meta: z.optional(
+    z.object({
+      animated: z.optional(z.boolean()),
+      blurhash: z.optional(z.string()),
+    }),
+  ),
+});
+
+const galleryResponseSchema = z.object({
+  data: z.object({
+    id: z.string(),
+    type: z.literal('gallery'),
+    attributes: z.object({
+      title: z.nullable(z.string()),
+      description: z.nullable(z.string()),
+      created: z.nullable(z.number()),
+      updated: z.nullable(z.number()),
+    }),
+    relationships: z.object({
+      images: z.object({ data: z.array(resourceObjectSchema) }),
+      cover: z.object({ data: z.nullable(resourceObjectSchema) }),
+    }),
+  }),
+  included: z.array(galleryImageSchema),
 });
 
 type ResourceObjectResponse = z.infer<typeof resourceObjectResponseSchema>;